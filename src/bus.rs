@@ -0,0 +1,131 @@
+use std::ops::RangeInclusive;
+
+// A memory mapped peripheral. Addresses are passed as offsets from the
+// start of the range the device is mapped at.
+pub trait Device: Send {
+    fn name(&self) -> &str;
+
+    // a load by the program, which may have side effects like popping a queue
+    fn read(&mut self, offset: u16) -> u16;
+
+    // what a read would return, without any side effects
+    fn peek(&self, offset: u16) -> u16;
+
+    fn write(&mut self, offset: u16, data: u16);
+
+    // called after every instruction with the cycles it took
+    fn tick(&mut self, _cycles: u64) {}
+
+    // whether the program has polled this device empty for so long that it is just waiting on it
+    fn is_waiting(&self) -> bool {
+        false
+    }
+
+    // the program stored something somewhere, so it is doing more than waiting
+    fn note_store(&mut self) {}
+}
+
+struct Mapping {
+    range: RangeInclusive<u16>,
+    device: Box<dyn Device>,
+    write_through: bool, // stores reach ram behind the device as well
+}
+
+// Maps address ranges to devices. Addresses that no device claims are ram.
+pub struct Bus {
+    mappings: Vec<Mapping>, // sorted by address, never overlapping
+}
+
+impl Bus {
+    pub fn new(devices: Vec<(RangeInclusive<u16>, Box<dyn Device>)>) -> Result<Bus, String> {
+        let mut bus = Bus { mappings: vec![] };
+        for (range, device) in devices {
+            bus.map(range, device)?;
+        }
+        Ok(bus)
+    }
+
+    // adds a device, unless its range overlaps one that is already mapped
+    pub fn map(&mut self, range: RangeInclusive<u16>, device: Box<dyn Device>) -> Result<(), String> {
+        if range.is_empty() {
+            return Err(format!("{} is mapped at an empty range", device.name()));
+        }
+        if let Some(other) = self.mappings.iter().find(|mapping| overlaps(&mapping.range, &range)) {
+            return Err(format!("{} at {:#06x}-{:#06x} overlaps {} at {:#06x}-{:#06x}",
+                device.name(), range.start(), range.end(),
+                other.device.name(), other.range.start(), other.range.end()));
+        }
+        let i = self.mappings.partition_point(|mapping| mapping.range.start() < range.start());
+        self.mappings.insert(i, Mapping { range, device, write_through: false });
+        Ok(())
+    }
+
+    // every device with the range it is mapped at, in address order
+    pub fn devices(&self) -> impl Iterator<Item = (RangeInclusive<u16>, &str)> {
+        self.mappings.iter().map(|mapping| (mapping.range.clone(), mapping.device.name()))
+    }
+
+    fn find(&self, addr: u16) -> Option<usize> {
+        let i = self.mappings.partition_point(|mapping| *mapping.range.start() <= addr);
+        let i = i.checked_sub(1)?;
+        self.mappings[i].range.contains(&addr).then_some(i)
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        self.find(addr).is_some()
+    }
+
+    pub fn read(&mut self, addr: u16) -> Option<u16> {
+        let i = self.find(addr)?;
+        let mapping = &mut self.mappings[i];
+        Some(mapping.device.read(addr - mapping.range.start()))
+    }
+
+    pub fn peek(&self, addr: u16) -> Option<u16> {
+        let mapping = &self.mappings[self.find(addr)?];
+        Some(mapping.device.peek(addr - mapping.range.start()))
+    }
+
+    // returns false if no device is mapped at the address
+    pub fn write(&mut self, addr: u16, data: u16) -> bool {
+        match self.find(addr) {
+            Some(i) => {
+                let mapping = &mut self.mappings[i];
+                mapping.device.write(addr - mapping.range.start(), data);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // whether stores to the device at an address also go to the ram behind it
+    pub fn set_write_through(&mut self, addr: u16, write_through: bool) {
+        if let Some(i) = self.find(addr) {
+            self.mappings[i].write_through = write_through;
+        }
+    }
+
+    pub fn writes_through(&self, addr: u16) -> bool {
+        self.find(addr).is_some_and(|i| self.mappings[i].write_through)
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        for mapping in &mut self.mappings {
+            mapping.device.tick(cycles);
+        }
+    }
+
+    pub fn is_waiting(&self, addr: u16) -> bool {
+        self.find(addr).is_some_and(|i| self.mappings[i].device.is_waiting())
+    }
+
+    pub fn note_store(&mut self) {
+        for mapping in &mut self.mappings {
+            mapping.device.note_store();
+        }
+    }
+}
+
+fn overlaps(a: &RangeInclusive<u16>, b: &RangeInclusive<u16>) -> bool {
+    a.start() <= b.end() && b.start() <= a.end()
+}
@@ -0,0 +1,136 @@
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// slow motion runs the clock at 1/n of its normal speed
+const SLOW_MOTION_DIVIDER : f64 = 4.0;
+// how often the pace is checked when there is no target clock
+const UNTHROTTLED_CHECK_INTERVAL : u64 = 0x1000;
+// the pace is checked about this often when there is a target clock
+const THROTTLE_GRANULARITY : Duration = Duration::from_millis(1);
+// the effective clock speed is averaged over this long
+const RATE_WINDOW : Duration = Duration::from_millis(500);
+// if the host falls further behind than this, give up catching up
+const MAX_LAG : Duration = Duration::from_millis(100);
+const PAUSE_POLL : Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SpeedMode {
+    #[default]
+    Normal,
+    Turbo,     // ignores the target clock
+    Paused,
+    SlowMotion,
+}
+
+// shared between the emulator thread, which measures the speed,
+// and the window, which shows it and switches modes on hotkeys
+#[derive(Default)]
+pub struct SpeedControl {
+    pub mode: SpeedMode,
+    pub effective_hz: f64,
+}
+
+// paces emulated cycles against wall time
+pub struct Throttle {
+    target_hz: Option<u64>,
+    control: Arc<RwLock<SpeedControl>>,
+    mode: SpeedMode,
+    next_check: u64,
+    // cycles and time when the current pace was set
+    anchor_cycles: u64,
+    anchor_time: Instant,
+    anchor_hz: Option<f64>,
+    // start of the current window for measuring the effective speed
+    rate_cycles: u64,
+    rate_time: Instant,
+}
+
+impl SpeedControl {
+    pub fn toggle(&mut self, mode: SpeedMode) {
+        self.mode = if self.mode == mode { SpeedMode::Normal } else { mode };
+    }
+}
+
+impl Throttle {
+    pub fn new(target_hz: Option<u64>, control: Arc<RwLock<SpeedControl>>) -> Throttle {
+        let now = Instant::now();
+        Throttle {
+            target_hz,
+            control,
+            mode: SpeedMode::Normal,
+            next_check: 0,
+            anchor_cycles: 0,
+            anchor_time: now,
+            anchor_hz: target_hz.map(|hz| hz as f64),
+            rate_cycles: 0,
+            rate_time: now,
+        }
+    }
+
+    // the clock rate the program is currently paced to, if any
+    pub fn paced_hz(&self) -> Option<f64> {
+        self.anchor_hz
+    }
+
+    // called before every instruction, sleeps when the emulator is ahead of the clock
+    pub fn pace(&mut self, cycle_count: u64) {
+        if cycle_count < self.next_check {
+            return;
+        }
+
+        let mode = self.control.read().unwrap().mode;
+        if mode == SpeedMode::Paused {
+            while self.control.read().unwrap().mode == SpeedMode::Paused {
+                thread::sleep(PAUSE_POLL);
+            }
+            self.rate_cycles = cycle_count;
+            self.rate_time = Instant::now();
+            return self.pace(cycle_count);
+        }
+        if mode != self.mode {
+            self.set_mode(mode, cycle_count);
+        }
+        self.measure(cycle_count);
+
+        match self.anchor_hz {
+            Some(hz) => {
+                let expected = Duration::from_secs_f64((cycle_count - self.anchor_cycles) as f64 / hz);
+                let actual = self.anchor_time.elapsed();
+                if expected > actual {
+                    thread::sleep(expected - actual);
+                } else if actual - expected > MAX_LAG {
+                    // the host can't keep up, so don't try to catch up in a burst later
+                    self.anchor_cycles = cycle_count;
+                    self.anchor_time = Instant::now();
+                }
+                self.next_check = cycle_count + ((hz * THROTTLE_GRANULARITY.as_secs_f64()) as u64).max(1);
+            },
+            None => self.next_check = cycle_count + UNTHROTTLED_CHECK_INTERVAL,
+        }
+    }
+
+    fn set_mode(&mut self, mode: SpeedMode, cycle_count: u64) {
+        // slow motion without a target clock slows down whatever speed we had
+        let base_hz = self.target_hz.map(|hz| hz as f64)
+            .unwrap_or(self.control.read().unwrap().effective_hz);
+        self.anchor_hz = match mode {
+            SpeedMode::Normal => self.target_hz.map(|hz| hz as f64),
+            SpeedMode::SlowMotion => Some(base_hz / SLOW_MOTION_DIVIDER).filter(|hz| *hz >= 1.0),
+            SpeedMode::Turbo | SpeedMode::Paused => None,
+        };
+        self.mode = mode;
+        self.anchor_cycles = cycle_count;
+        self.anchor_time = Instant::now();
+    }
+
+    fn measure(&mut self, cycle_count: u64) {
+        let elapsed = self.rate_time.elapsed();
+        if elapsed >= RATE_WINDOW {
+            let hz = (cycle_count - self.rate_cycles) as f64 / elapsed.as_secs_f64();
+            self.control.write().unwrap().effective_hz = hz;
+            self.rate_cycles = cycle_count;
+            self.rate_time = Instant::now();
+        }
+    }
+}
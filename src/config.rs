@@ -0,0 +1,153 @@
+use std::fs;
+use std::ops::RangeInclusive;
+
+// a small subset of TOML: [sections] of key = value lines with # comments,
+// where a value is an integer (decimal, 0x hex or 0b binary), a bool or a "string"
+pub struct Config {
+    pub sections: Vec<Section>,
+}
+
+pub struct Section {
+    pub name: String, // empty for keys before the first [section]
+    pub entries: Vec<Entry>,
+}
+
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    Bool(bool),
+    String(String),
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        Config::parse(&text).map_err(|e| format!("{path}: {e}"))
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut sections = vec![Section { name: String::new(), entries: vec![] }];
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[') {
+                let name = name.strip_suffix(']')
+                    .ok_or(format!("line {line_number}: unterminated section header"))?;
+                sections.push(Section { name: name.trim().to_string(), entries: vec![] });
+                continue;
+            }
+
+            let (key, value) = line.split_once('=')
+                .ok_or(format!("line {line_number}: expected key = value"))?;
+            let key = unquote(key.trim()).unwrap_or(key.trim()).to_string();
+            let value = Value::parse(value.trim())
+                .map_err(|e| format!("line {line_number}: {e}"))?;
+            sections.last_mut().unwrap().entries.push(Entry { key, value, line: line_number });
+        }
+        Ok(Config { sections })
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+}
+
+impl Entry {
+    pub fn as_u64(&self) -> Result<u64, String> {
+        match self.value {
+            Value::Integer(n) if n >= 0 => Ok(n as u64),
+            _ => Err(self.error("expected a positive integer")),
+        }
+    }
+
+    pub fn as_u16(&self) -> Result<u16, String> {
+        self.as_u64()?.try_into().map_err(|_| self.error("expected a 16 bit value"))
+    }
+
+    pub fn as_bool(&self) -> Result<bool, String> {
+        match self.value {
+            Value::Bool(b) => Ok(b),
+            _ => Err(self.error("expected true or false")),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str, String> {
+        match &self.value {
+            Value::String(s) => Ok(s),
+            _ => Err(self.error("expected a string")),
+        }
+    }
+
+    pub fn error(&self, message: &str) -> String {
+        format!("line {}: {}: {}", self.line, self.key, message)
+    }
+}
+
+impl Value {
+    fn parse(text: &str) -> Result<Value, String> {
+        if let Some(s) = unquote(text) {
+            return Ok(Value::String(s.to_string()));
+        }
+        match text {
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            _ => {}
+        }
+        parse_integer(text).map(Value::Integer).ok_or(format!("invalid value {text}"))
+    }
+}
+
+// parses decimal, 0x hex and 0b binary numbers, with _ allowed as a separator
+pub fn parse_integer(text: &str) -> Option<i64> {
+    let text = text.replace('_', "");
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.as_str()),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse::<i64>().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+// parses an inclusive address range like 0xC000-0xDFFF, or a single address
+pub fn parse_range(text: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = text.split_once('-').unwrap_or((text, text));
+    let start = u16::try_from(parse_integer(start.trim())?).ok()?;
+    let end = u16::try_from(parse_integer(end.trim())?).ok()?;
+    if start > end {
+        return None;
+    }
+    Some(start..=end)
+}
+
+fn unquote(text: &str) -> Option<&str> {
+    text.strip_prefix('"')?.strip_suffix('"')
+}
+
+fn strip_comment(line: &str) -> &str {
+    // a # inside a string is not a comment
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
@@ -0,0 +1,133 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Write};
+
+use crate::symbols::SymbolTable;
+
+// Tracks which instructions ran and which way each conditional branch went.
+// With a source map the report is an lcov tracefile, otherwise it is a
+// listing of the executed and unexecuted address ranges of the program.
+pub struct Coverage {
+    report_path: String,
+    executions: Vec<u64>,                // per address
+    branches: HashMap<u16, (u64, u64)>,  // times taken and not taken
+}
+
+// what the tracefile says about one source file
+#[derive(Default)]
+struct SourceFile {
+    lines: BTreeMap<u32, u64>,                  // hits per line
+    functions: Vec<(u32, String, u64)>,         // line, name and hits
+    branches: Vec<(u32, u16, (u64, u64))>,      // line, address and directions
+}
+
+impl Coverage {
+    pub fn new(report_path: &str) -> Coverage {
+        Coverage {
+            report_path: report_path.to_string(),
+            executions: vec![0; 1 << 16],
+            branches: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, pc: u16, instr: u16, taken: bool) {
+        self.executions[usize::from(pc)] += 1;
+        // jmp always goes the same way
+        let conditional = instr >> 13 == 6 && (instr >> 7) & 0b111111 != 6;
+        if conditional {
+            let directions = self.branches.entry(pc).or_insert((0, 0));
+            if taken {
+                directions.0 += 1;
+            } else {
+                directions.1 += 1;
+            }
+        }
+    }
+
+    pub fn write_report(&self, symbols: &SymbolTable, program_size: usize) -> Result<(), io::Error> {
+        let mut file = File::create(&self.report_path)?;
+        if symbols.has_source_lines() {
+            self.write_lcov(&mut file, symbols, program_size)
+        } else {
+            self.write_listing(&mut file, symbols, program_size)
+        }
+    }
+
+    fn write_lcov(&self, file: &mut File, symbols: &SymbolTable, program_size: usize) -> Result<(), io::Error> {
+        let mut sources: BTreeMap<&str, SourceFile> = BTreeMap::new();
+
+        // a line covers the addresses up to where the next one starts
+        let lines: Vec<(u16, &str, u32)> = symbols.source_lines().collect();
+        for (i, &(start, name, line)) in lines.iter().enumerate() {
+            let end = lines.get(i + 1).map_or(program_size, |&(next, _, _)| usize::from(next));
+            let hits = self.executions[usize::from(start)..end.max(usize::from(start))].iter().copied().max().unwrap_or(0);
+            let source = sources.entry(name).or_default();
+            let line_hits = source.lines.entry(line).or_insert(0);
+            *line_hits = (*line_hits).max(hits);
+        }
+        for (address, name) in symbols.symbols() {
+            if let Some((source, line)) = symbols.source_line(address) {
+                let hits = self.executions[usize::from(address)];
+                sources.entry(source).or_default().functions.push((line, name.to_string(), hits));
+            }
+        }
+        let mut branches: Vec<(&u16, &(u64, u64))> = self.branches.iter().collect();
+        branches.sort();
+        for (&address, &directions) in branches {
+            if let Some((source, line)) = symbols.source_line(address) {
+                sources.entry(source).or_default().branches.push((line, address, directions));
+            }
+        }
+
+        for (name, source) in &sources {
+            writeln!(file, "TN:")?;
+            writeln!(file, "SF:{}", name)?;
+            for (line, function, _) in &source.functions {
+                writeln!(file, "FN:{},{}", line, function)?;
+            }
+            for (_, function, hits) in &source.functions {
+                writeln!(file, "FNDA:{},{}", hits, function)?;
+            }
+            writeln!(file, "FNF:{}", source.functions.len())?;
+            writeln!(file, "FNH:{}", source.functions.iter().filter(|(_, _, hits)| *hits > 0).count())?;
+            let mut branches_hit = 0;
+            // branches only show up once they have run, so each has a count
+            for (line, address, (taken, not_taken)) in &source.branches {
+                writeln!(file, "BRDA:{},{},0,{}", line, address, taken)?;
+                writeln!(file, "BRDA:{},{},1,{}", line, address, not_taken)?;
+                branches_hit += u64::from(*taken > 0) + u64::from(*not_taken > 0);
+            }
+            writeln!(file, "BRF:{}", source.branches.len() * 2)?;
+            writeln!(file, "BRH:{}", branches_hit)?;
+            for (line, hits) in &source.lines {
+                writeln!(file, "DA:{},{}", line, hits)?;
+            }
+            writeln!(file, "LF:{}", source.lines.len())?;
+            writeln!(file, "LH:{}", source.lines.values().filter(|hits| **hits > 0).count())?;
+            writeln!(file, "end_of_record")?;
+        }
+        Ok(())
+    }
+
+    fn write_listing(&self, file: &mut File, symbols: &SymbolTable, program_size: usize) -> Result<(), io::Error> {
+        let executed = self.executions[..program_size].iter().filter(|count| **count > 0).count();
+        writeln!(file, "executed {} of {} words of the program", executed, program_size)?;
+
+        // runs of addresses that either all ran or all didn't
+        let mut start = 0;
+        while start < program_size {
+            let ran = self.executions[start] > 0;
+            let end = (start..program_size).find(|&address| (self.executions[address] > 0) != ran).unwrap_or(program_size);
+            writeln!(file, "{:#06x}-{:#06x} {}", start, end - 1, if ran { "executed" } else { "not executed" })?;
+            start = end;
+        }
+
+        writeln!(file, "\nconditional branches:")?;
+        let mut branches: Vec<(&u16, &(u64, u64))> = self.branches.iter().collect();
+        branches.sort();
+        for (&address, &(taken, not_taken)) in branches {
+            writeln!(file, "{}: taken {}, not taken {}", symbols.describe(address), taken, not_taken)?;
+        }
+        Ok(())
+    }
+}
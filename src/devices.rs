@@ -0,0 +1,512 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::{Arc, RwLock};
+
+use crate::bus::Device;
+use crate::memory::{FrameBuffer, TileMap, SpriteMap, Palette, Collisions, Bitmap, Console, BITMAP_WINDOW_SIZE, TEXT_ECHO, TEXT_COLUMNS, TEXT_ROWS};
+
+// this many empty reads of the input port in a row, with no
+// stores in between, means the program is just waiting for input
+const IDLE_POLL_THRESHOLD : u32 = 0x100;
+
+// the pixels of every tile, one word per pixel
+pub struct TileMapPort {
+    tile_map: Arc<RwLock<TileMap>>,
+}
+
+// the tile shown at each position of the background, two per word
+pub struct FrameBufferPort {
+    frame_buffer: Arc<RwLock<FrameBuffer>>,
+}
+
+// the flip, priority and palette bits of every tile of the background, four per word
+pub struct TileAttributesPort {
+    frame_buffer: Arc<RwLock<FrameBuffer>>,
+}
+
+// the pixels of every sprite, one word per pixel
+pub struct SpriteDataPort {
+    sprite_map: Arc<RwLock<SpriteMap>>,
+}
+
+// the x and y coordinates of every sprite, in consecutive words
+pub struct SpriteRegisters {
+    sprite_map: Arc<RwLock<SpriteMap>>,
+}
+
+// the x, y, control and pointer words of every sprite
+pub struct SpriteTable {
+    sprite_map: Arc<RwLock<SpriteMap>>,
+}
+
+// the sprite collision bits, low word first: the sprites that touched another
+// sprite, those that touched the background, then the sprites each one touched.
+// Reading a word clears it.
+pub struct CollisionRegisters {
+    collisions: Arc<RwLock<Collisions>>,
+}
+
+// the part of the bitmap the bank register selects
+pub struct BitmapWindow {
+    bitmap: Arc<RwLock<Bitmap>>,
+    bank: Arc<RwLock<u16>>,
+}
+
+// a single word register read by the window, like the scroll registers
+pub struct Register {
+    name: String,
+    value: Arc<RwLock<u16>>,
+}
+
+// keys pressed in the window, one per read, 0 when there are none
+pub struct Ps2Keyboard {
+    buffer: Arc<RwLock<VecDeque<u16>>>,
+    empty_polls: u32,
+}
+
+// characters written here go to stdout
+pub struct Uart;
+
+// the uart, also printing on the screen in text mode when the console echoes it
+pub struct ConsoleUart {
+    uart: Uart,
+    console: Arc<RwLock<Console>>,
+    grid: Arc<RwLock<Bitmap>>,
+}
+
+// the cursor column and row, control and attribute of text mode, and
+// a scroll register that moves the text up the lines written to it
+pub struct TextRegisters {
+    console: Arc<RwLock<Console>>,
+    grid: Arc<RwLock<Bitmap>>,
+}
+
+// the colours of the palette, one word each
+pub struct PaletteRam {
+    palette: Arc<RwLock<Palette>>,
+}
+
+// which tiles and then which sprites are in indexed mode, 16 to a word
+pub struct PaletteModes {
+    palette: Arc<RwLock<Palette>>,
+}
+
+impl TileMapPort {
+    pub fn new(tile_map: Arc<RwLock<TileMap>>) -> TileMapPort {
+        TileMapPort { tile_map }
+    }
+}
+
+impl Device for TileMapPort {
+    fn name(&self) -> &str {
+        "tile map"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        self.tile_map.read().unwrap().get_tile_word(u32::from(offset))
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        self.tile_map.write().unwrap().set_tile_word(u32::from(offset), data);
+    }
+}
+
+impl FrameBufferPort {
+    pub fn new(frame_buffer: Arc<RwLock<FrameBuffer>>) -> FrameBufferPort {
+        FrameBufferPort { frame_buffer }
+    }
+}
+
+impl Device for FrameBufferPort {
+    fn name(&self) -> &str {
+        "frame buffer"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        self.frame_buffer.read().unwrap().get_tile_pair(u32::from(offset))
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        self.frame_buffer.write().unwrap().set_tile_pair(u32::from(offset), data);
+    }
+}
+
+impl TileAttributesPort {
+    pub fn new(frame_buffer: Arc<RwLock<FrameBuffer>>) -> TileAttributesPort {
+        TileAttributesPort { frame_buffer }
+    }
+}
+
+impl Device for TileAttributesPort {
+    fn name(&self) -> &str {
+        "tile attributes"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        self.frame_buffer.read().unwrap().get_attribute_word(u32::from(offset))
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        self.frame_buffer.write().unwrap().set_attribute_word(u32::from(offset), data);
+    }
+}
+
+impl SpriteDataPort {
+    pub fn new(sprite_map: Arc<RwLock<SpriteMap>>) -> SpriteDataPort {
+        SpriteDataPort { sprite_map }
+    }
+}
+
+impl Device for SpriteDataPort {
+    fn name(&self) -> &str {
+        "sprite map"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        self.sprite_map.read().unwrap().get_sprite_word(u32::from(offset))
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        self.sprite_map.write().unwrap().set_sprite_word(u32::from(offset), data);
+    }
+}
+
+impl SpriteRegisters {
+    pub fn new(sprite_map: Arc<RwLock<SpriteMap>>) -> SpriteRegisters {
+        SpriteRegisters { sprite_map }
+    }
+}
+
+impl Device for SpriteRegisters {
+    fn name(&self) -> &str {
+        "sprite registers"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        self.sprite_map.read().unwrap().get_sprite_reg(u32::from(offset))
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        self.sprite_map.write().unwrap().set_sprite_reg(u32::from(offset), data);
+    }
+}
+
+impl SpriteTable {
+    pub fn new(sprite_map: Arc<RwLock<SpriteMap>>) -> SpriteTable {
+        SpriteTable { sprite_map }
+    }
+}
+
+impl Device for SpriteTable {
+    fn name(&self) -> &str {
+        "sprite table"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        self.sprite_map.read().unwrap().get_sprite_entry(u32::from(offset))
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        self.sprite_map.write().unwrap().set_sprite_entry(u32::from(offset), data);
+    }
+}
+
+impl CollisionRegisters {
+    pub fn new(collisions: Arc<RwLock<Collisions>>) -> CollisionRegisters {
+        CollisionRegisters { collisions }
+    }
+}
+
+impl Device for CollisionRegisters {
+    fn name(&self) -> &str {
+        "collisions"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        let mut collisions = self.collisions.write().unwrap();
+        let bits = match offset / 2 {
+            0 => &mut collisions.sprites,
+            1 => &mut collisions.background,
+            n => &mut collisions.pairs[usize::from(n - 2)],
+        };
+        let shift = offset % 2 * 16;
+        let word = (*bits >> shift) as u16;
+        *bits &= !(0xFFFF << shift);
+        word
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        let collisions = self.collisions.read().unwrap();
+        let bits = match offset / 2 {
+            0 => collisions.sprites,
+            1 => collisions.background,
+            n => collisions.pairs[usize::from(n - 2)],
+        };
+        (bits >> (offset % 2 * 16)) as u16
+    }
+
+    fn write(&mut self, _offset: u16, _data: u16) {}
+}
+
+impl BitmapWindow {
+    pub fn new(bitmap: Arc<RwLock<Bitmap>>, bank: Arc<RwLock<u16>>) -> BitmapWindow {
+        BitmapWindow { bitmap, bank }
+    }
+
+    fn word(&self, offset: u16) -> u32 {
+        u32::from(*self.bank.read().unwrap()) * BITMAP_WINDOW_SIZE as u32 + u32::from(offset)
+    }
+}
+
+impl Device for BitmapWindow {
+    fn name(&self) -> &str {
+        "bitmap"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        self.bitmap.read().unwrap().get_word(self.word(offset))
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        let word = self.word(offset);
+        self.bitmap.write().unwrap().set_word(word, data);
+    }
+}
+
+impl Register {
+    pub fn new(name: &str, value: Arc<RwLock<u16>>) -> Register {
+        Register { name: name.to_string(), value }
+    }
+}
+
+impl Device for Register {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, _offset: u16) -> u16 {
+        *self.value.read().unwrap()
+    }
+
+    fn write(&mut self, _offset: u16, data: u16) {
+        *self.value.write().unwrap() = data;
+    }
+}
+
+impl Ps2Keyboard {
+    pub fn new(buffer: Arc<RwLock<VecDeque<u16>>>) -> Ps2Keyboard {
+        Ps2Keyboard { buffer, empty_polls: 0 }
+    }
+}
+
+impl Device for Ps2Keyboard {
+    fn name(&self) -> &str {
+        "ps2 keyboard"
+    }
+
+    fn read(&mut self, _offset: u16) -> u16 {
+        let key = self.buffer.write().unwrap().pop_front().unwrap_or(0);
+        self.empty_polls = if key == 0 { self.empty_polls.saturating_add(1) } else { 0 };
+        key
+    }
+
+    fn peek(&self, _offset: u16) -> u16 {
+        self.buffer.read().unwrap().front().copied().unwrap_or(0)
+    }
+
+    fn write(&mut self, _offset: u16, _data: u16) {
+        panic!("attempting to write to read input port");
+    }
+
+    fn is_waiting(&self) -> bool {
+        self.empty_polls >= IDLE_POLL_THRESHOLD
+    }
+
+    fn note_store(&mut self) {
+        self.empty_polls = 0;
+    }
+}
+
+impl Device for Uart {
+    fn name(&self) -> &str {
+        "uart"
+    }
+
+    fn read(&mut self, _offset: u16) -> u16 {
+        panic!("attempting to read output port");
+    }
+
+    fn peek(&self, _offset: u16) -> u16 {
+        0
+    }
+
+    fn write(&mut self, _offset: u16, data: u16) {
+        print!("{}", (data as u8) as char);
+        io::stdout().flush().unwrap();
+    }
+}
+
+impl ConsoleUart {
+    pub fn new(console: Arc<RwLock<Console>>, grid: Arc<RwLock<Bitmap>>) -> ConsoleUart {
+        ConsoleUart { uart: Uart, console, grid }
+    }
+}
+
+impl Device for ConsoleUart {
+    fn name(&self) -> &str {
+        self.uart.name()
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.uart.read(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        self.uart.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        self.uart.write(offset, data);
+        let mut console = self.console.write().unwrap();
+        if console.control & TEXT_ECHO != 0 {
+            console.put_char(&mut self.grid.write().unwrap(), data as u8);
+        }
+    }
+}
+
+impl TextRegisters {
+    pub fn new(console: Arc<RwLock<Console>>, grid: Arc<RwLock<Bitmap>>) -> TextRegisters {
+        TextRegisters { console, grid }
+    }
+}
+
+impl Device for TextRegisters {
+    fn name(&self) -> &str {
+        "text"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        let console = self.console.read().unwrap();
+        match offset {
+            0 => console.cursor_x,
+            1 => console.cursor_y,
+            2 => console.control,
+            3 => console.attribute,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        let mut console = self.console.write().unwrap();
+        match offset {
+            // a cursor off the screen would be moved back at the next character anyway
+            0 => console.cursor_x = data.min(TEXT_COLUMNS as u16 - 1),
+            1 => console.cursor_y = data.min(TEXT_ROWS as u16 - 1),
+            2 => console.control = data,
+            3 => console.attribute = data,
+            _ => console.scroll(&mut self.grid.write().unwrap(), data),
+        }
+    }
+}
+
+impl PaletteRam {
+    pub fn new(palette: Arc<RwLock<Palette>>) -> PaletteRam {
+        PaletteRam { palette }
+    }
+}
+
+impl Device for PaletteRam {
+    fn name(&self) -> &str {
+        "palette"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        self.palette.read().unwrap().colors[usize::from(offset)]
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        self.palette.write().unwrap().colors[usize::from(offset)] = data;
+    }
+}
+
+impl PaletteModes {
+    pub fn new(palette: Arc<RwLock<Palette>>) -> PaletteModes {
+        PaletteModes { palette }
+    }
+}
+
+impl Device for PaletteModes {
+    fn name(&self) -> &str {
+        "palette modes"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        let palette = self.palette.read().unwrap();
+        let first = usize::from(offset) * 16;
+        let tiles = palette.indexed_tiles.len();
+        let modes = if first < tiles {
+            &palette.indexed_tiles[first..]
+        } else {
+            &palette.indexed_sprites[first - tiles..]
+        };
+        modes.iter().take(16).enumerate().fold(0, |word, (i, &indexed)| word | (u16::from(indexed) << i))
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        let mut palette = self.palette.write().unwrap();
+        let first = usize::from(offset) * 16;
+        let tiles = palette.indexed_tiles.len();
+        let modes = if first < tiles {
+            &mut palette.indexed_tiles[first..]
+        } else {
+            &mut palette.indexed_sprites[first - tiles..]
+        };
+        for (i, indexed) in modes.iter_mut().take(16).enumerate() {
+            *indexed = data & (1 << i) != 0;
+        }
+    }
+}
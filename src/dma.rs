@@ -0,0 +1,138 @@
+use std::sync::{Arc, RwLock};
+
+use crate::bus::Device;
+
+// the commands written to the control register
+pub const DMA_COPY : u16 = 1;
+pub const DMA_FILL : u16 = 2;
+// a bit of the status read from the control register
+pub const DMA_BUSY : u16 = 1 << 0;
+// without a configured cost, each word moved takes this many cycles
+pub const DEFAULT_DMA_CYCLES : u64 = 1;
+
+// A block of words to copy or fill: rows of length words, with each row
+// starting stride words after the one before it.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Transfer {
+    pub source: u16,
+    pub destination: u16,
+    pub length: u16,
+    pub rows: u16,
+    pub source_stride: u16,
+    pub destination_stride: u16,
+    pub fill: Option<u16>, // written to every word instead of copying
+}
+
+// The registers of the dma engine and the transfer it is running. A
+// transfer runs alongside the program for as many cycles as it moves words,
+// then memory carries it out and raises the dma interrupt.
+pub struct Dma {
+    registers: [u16; 7], // source, destination, length, rows, source and destination stride, fill value
+    cycles_per_word: u64,
+    running: Option<Transfer>,
+    remaining: u64, // cycles until the running transfer completes
+}
+
+// source, destination, length, rows, source stride, destination stride,
+// fill value, then the control register: write a command, read the status
+pub struct DmaController {
+    dma: Arc<RwLock<Dma>>,
+}
+
+impl Transfer {
+    // every address written, with the address read or the fill value
+    pub fn words(&self) -> impl Iterator<Item = (u16, Result<u16, u16>)> + '_ {
+        (0..self.rows).flat_map(move |row| (0..self.length).map(move |i| {
+            let destination = self.destination.wrapping_add(row.wrapping_mul(self.destination_stride)).wrapping_add(i);
+            let source = self.source.wrapping_add(row.wrapping_mul(self.source_stride)).wrapping_add(i);
+            (destination, self.fill.ok_or(source))
+        }))
+    }
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        Dma {
+            registers: [0, 0, 0, 1, 0, 0, 0],
+            cycles_per_word: DEFAULT_DMA_CYCLES,
+            running: None,
+            remaining: 0,
+        }
+    }
+
+    pub fn set_cycles_per_word(&mut self, cycles: u64) {
+        self.cycles_per_word = cycles;
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.running.is_some()
+    }
+
+    fn start(&mut self, command: u16) {
+        if self.is_busy() || (command != DMA_COPY && command != DMA_FILL) {
+            return;
+        }
+        let [source, destination, length, rows, source_stride, destination_stride, fill] = self.registers;
+        let transfer = Transfer {
+            source,
+            destination,
+            length,
+            rows,
+            source_stride,
+            destination_stride,
+            fill: (command == DMA_FILL).then_some(fill),
+        };
+        let words = u64::from(length) * u64::from(rows);
+        self.remaining = (words * self.cycles_per_word).max(1);
+        self.running = Some(transfer);
+    }
+
+    // the transfer that completes within these cycles, if any
+    pub fn tick(&mut self, cycles: u64) -> Option<Transfer> {
+        self.running?;
+        self.remaining = self.remaining.saturating_sub(cycles);
+        if self.remaining > 0 {
+            return None;
+        }
+        self.running.take()
+    }
+}
+
+impl Default for Dma {
+    fn default() -> Dma {
+        Dma::new()
+    }
+}
+
+impl DmaController {
+    pub fn new(dma: Arc<RwLock<Dma>>) -> DmaController {
+        DmaController { dma }
+    }
+}
+
+impl Device for DmaController {
+    fn name(&self) -> &str {
+        "dma"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        let dma = self.dma.read().unwrap();
+        match dma.registers.get(usize::from(offset)) {
+            Some(&value) => value,
+            None if dma.is_busy() => DMA_BUSY,
+            None => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        let mut dma = self.dma.write().unwrap();
+        match dma.registers.get_mut(usize::from(offset)) {
+            Some(register) => *register = data,
+            None => dma.start(data),
+        }
+    }
+}
@@ -1,10 +1,31 @@
-use std::sync::{Arc, Mutex};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::memory::Memory;
+use crate::memory::{Memory, Watchpoint, WatchHit, WatchKind};
 use crate::graphics::Graphics;
-
-use crate::memory::STACK_START;
+use crate::bus::Device;
+use crate::clock::{SpeedControl, Throttle};
+use crate::timing::TimingModel;
+use crate::pipeline::Pipeline;
+use crate::profiler::Profiler;
+use crate::coverage::Coverage;
+use crate::symbols::SymbolTable;
+
+use crate::machine::Machine;
+use crate::interrupts::Interrupts;
+use crate::video::VideoControl;
+use crate::viewers;
+
+// number of recently executed instructions kept for stop reports
+const TRACE_LENGTH : usize = 16;
+// the wall clock is only sampled every this many cycles
+const TIMEOUT_CHECK_INTERVAL : u64 = 0x1000;
+// longest an idle program sleeps before it is resumed, one frame
+const IDLE_SLEEP : Duration = Duration::from_micros(1_000_000 / 60);
 
 pub struct Emulator {
   regfile : [u16; 8],
@@ -13,10 +34,79 @@ pub struct Emulator {
   flags : [bool; 4], // flags are: carry | zero | sign | overflow
   halted : bool,
   cycle_count : u64,
+  max_cycles : Option<u64>,
+  timeout : Option<Duration>,
+  next_timeout_check : u64, // cycle count at which the wall clock is next read
+  clock_hz : Option<u64>,
+  timing : TimingModel,
+  pipeline : Option<Pipeline>,
+  profiler : Option<Profiler>,
+  coverage : Option<Coverage>,
+  views_dir : Option<String>, // where to save the debug views when the program stops
+  symbols : Arc<SymbolTable>,
+  stack_limit : Option<u16>,
+  stack_base : u16,  // r1 when the program started
+  stack_depth : u16, // deepest the stack has been, in words
+  sanitizer : Option<Sanitizer>,
+  warned : HashSet<u16>, // instructions already warned about
+  warned_smc : HashSet<u16>, // modified addresses already warned about
+  stack_start : u16, // where r1 and r2 start with a window
+  initial_registers : [Option<u16>; 8],
+  interrupts : Arc<Interrupts>,
+  video : Arc<VideoControl>,
+  interrupt_return : Option<(u16, [bool; 4])>, // pc and flags to go back to from a handler
+  trace : VecDeque<TraceEntry>,
+}
+
+// why the run loop stopped
+#[derive(Debug)]
+pub enum StopReason {
+  Halted(u16), // sys EXIT, holds the value in r3
+  CycleLimit(StopState),
+  Timeout(StopState),
+  Idle(StopState), // spinning with nothing that could wake it up
+  Watchpoint(WatchHit, StopState), // stopped at the instruction that hit it
+  StackOverflow(u16, StopState), // a store through r1 below the stack limit, before it happened
+  UninitializedRead(u16, StopState), // a lw of ram nothing has written, at the lw
+  ProtectionFault(u16, StopState), // a sw or dma write into the protected program, before it happened
+}
+
+// what to do when a lw reads ram that nothing has written
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Sanitizer {
+  Warn, // once per instruction, then keep going
+  Trap,
+}
+
+// where the emulator was when it was stopped from the outside
+#[derive(Debug)]
+pub struct StopState {
+  pub pc : u16,
+  pub cycle_count : u64,
+  pub trace : Vec<TraceEntry>, // oldest first
+  pub symbols : Arc<SymbolTable>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEntry {
+  pub pc : u16,
+  pub instr : u16,
+}
+
+// lets an idle emulator sleep until the window has news for it
+#[derive(Default)]
+pub struct Wakeup {
+  pending: Mutex<bool>,
+  signal: Condvar,
 }
 
 impl Emulator {
   pub fn new(path: &str, datapath: &str) -> Emulator {
+    Emulator::with_machine(path, &Machine::standard(datapath))
+  }
+
+  // emulates a board revision described by a machine file instead of the standard one
+  pub fn with_machine(path: &str, machine: &Machine) -> Emulator {
     // read in binary file
     let bytes = std::fs::read(path).unwrap();
 
@@ -28,65 +118,383 @@ impl Emulator {
       let short = u16::from_le_bytes([byte_pair[0], byte_pair[1]]);
       instructions.push(short);
     }
-    let mem: Memory = Memory::new(instructions, datapath);
+    let mem: Memory = Memory::with_machine(instructions, machine);
+    let interrupts = mem.get_interrupts();
+    let video = mem.get_video_control();
+
+    // symbols and source lines for diagnostics, if the binary has any next to it
+    let symbols = SymbolTable::load_for(path).unwrap_or_else(|e| {
+      eprintln!("Failed to load symbols: {}", e);
+      SymbolTable::default()
+    });
 
     Emulator {
       regfile: [0, 0, 0, 0, 0, 0, 0, 0],
       memory: mem,
-      pc: 0,
+      pc: machine.pc,
       flags: [false, false, false, false],
       halted: false,
       cycle_count: 0,
+      max_cycles: None,
+      timeout: None,
+      next_timeout_check: 0,
+      clock_hz: None,
+      timing: TimingModel::default(),
+      pipeline: None,
+      profiler: None,
+      coverage: None,
+      views_dir: None,
+      symbols: Arc::new(symbols),
+      stack_limit: None,
+      stack_base: 0,
+      stack_depth: 0,
+      sanitizer: None,
+      warned: HashSet::new(),
+      warned_smc: HashSet::new(),
+      stack_start: machine.stack_start,
+      initial_registers: machine.registers,
+      interrupts,
+      video,
+      interrupt_return: None,
+      trace: VecDeque::with_capacity(TRACE_LENGTH),
+    }
+  }
+
+  // stop with StopReason::CycleLimit once this many cycles have run
+  pub fn set_max_cycles(&mut self, max_cycles: Option<u64>) {
+    self.max_cycles = max_cycles;
+  }
+
+  // stop with StopReason::Timeout once this much wall time has passed
+  pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+    self.timeout = timeout;
+  }
+
+  // pace the program to this many cycles per second instead of running flat out
+  pub fn set_clock_hz(&mut self, clock_hz: Option<u64>) {
+    self.clock_hz = clock_hz;
+    if let Some(clock_hz) = clock_hz {
+      self.video.set_frame_cycles(clock_hz / 60);
+    }
+  }
+
+  // emulated cycles per frame of video, when the clock doesn't decide them
+  pub fn set_frame_cycles(&mut self, frame_cycles: u64) {
+    self.video.set_frame_cycles(frame_cycles);
+  }
+
+  pub fn set_timing(&mut self, timing: TimingModel) {
+    self.timing = timing;
+  }
+
+  // simulate the hardware pipeline and write its statistics when the program stops
+  pub fn set_pipeline(&mut self, pipeline: Option<Pipeline>) {
+    self.pipeline = pipeline;
+  }
+
+  // count where the cycles go and write a report when the program stops
+  pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+    self.profiler = profiler;
+  }
+
+  // track which instructions and branch directions ran and write a report when the program stops
+  pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+    self.coverage = coverage;
+  }
+
+  // saves the tile sheet, sprite boxes and tile map as pngs in a directory when the program stops
+  pub fn set_export_views(&mut self, dir: Option<String>) {
+    self.views_dir = dir;
+  }
+
+  // stop with StopReason::Watchpoint when a watched address is accessed
+  pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+    self.memory.add_watchpoint(watchpoint);
+  }
+
+  // stop with StopReason::StackOverflow before a store through r1 below this address
+  pub fn set_stack_limit(&mut self, stack_limit: Option<u16>) {
+    self.stack_limit = stack_limit;
+  }
+
+  // report lw reads of ram that neither the program, mem.hex nor a store has written
+  pub fn set_sanitizer(&mut self, sanitizer: Option<Sanitizer>) {
+    self.sanitizer = sanitizer;
+    self.memory.set_sanitize(sanitizer.is_some());
+  }
+
+  // stop with StopReason::ProtectionFault before a store into the program outside the writable ranges
+  pub fn protect_code(&mut self, writable: Vec<RangeInclusive<u16>>) {
+    self.memory.protect_code(writable);
+  }
+
+  // warn when an instruction a store has modified since load is executed
+  pub fn set_detect_smc(&mut self, detect_smc: bool) {
+    self.memory.set_detect_smc(detect_smc);
+  }
+
+  // starts in text mode with what the program writes to the uart printed on the screen too
+  pub fn start_console(&mut self) {
+    self.memory.start_console();
+  }
+
+  // how many cycles the dma engine takes per word it moves
+  pub fn set_dma_cycles(&mut self, cycles_per_word: u64) {
+    self.memory.set_dma_cycles(cycles_per_word);
+  }
+
+  // adds a custom memory mapped peripheral, unless it overlaps one that is already mapped
+  pub fn map_device(&mut self, range: RangeInclusive<u16>, device: Box<dyn Device>) -> Result<(), String> {
+    self.memory.map_device(range, device)
+  }
+
+  pub fn set_symbols(&mut self, symbols: SymbolTable) {
+    self.symbols = Arc::new(symbols);
+  }
+
+  // runs the program and returns the value in r3,
+  // panics if the program is stopped before it exits
+  pub fn run(self, with_graphics: bool) -> u16 {
+    match self.run_until_stop(with_graphics) {
+      StopReason::Halted(result) => result,
+      reason => panic!("{}", reason),
     }
   }
 
-  pub fn run(mut self, with_graphics: bool) -> u16 {
+  pub fn run_until_stop(mut self, with_graphics: bool) -> StopReason {
+
+    let speed = Arc::new(RwLock::new(SpeedControl::default()));
+    let mut throttle = Throttle::new(self.clock_hz, Arc::clone(&speed));
 
     let mut graphics: Option<Graphics> = None;
+    let mut wakeup: Option<Arc<Wakeup>> = None;
     if with_graphics {
-      graphics = Some(Graphics::new(
-        self.memory.get_frame_buffer(), 
-        self.memory.get_tile_map(), 
-        self.memory.get_io_buffer(),
-        self.memory.get_vscroll_register(),
-        self.memory.get_hscroll_register(),
-        self.memory.get_sprite_map(),
-        self.memory.get_scale_register(),
-      ));
+      wakeup = Some(Arc::new(Wakeup::default()));
+      graphics = Some(Graphics::new(&self.memory, Arc::clone(wakeup.as_ref().unwrap()), speed));
 
       // Graphics will occupy the upper address space so we need to
       // start the stack and base pointers at a different address
-      self.regfile[1] = STACK_START as u16;  // stack pointer
-      self.regfile[2] = STACK_START as u16;  // base pointer
+      self.regfile[1] = self.stack_start;  // stack pointer
+      self.regfile[2] = self.stack_start;  // base pointer
+
+      // frames are rendered for the window, and without a clock
+      // to count them in the window decides when they start
+      self.video.set_rendering(true);
+      self.video.set_window_driven(self.clock_hz.is_none());
+    }
+    for (register, value) in self.initial_registers.iter().enumerate() {
+      if let Some(value) = value {
+        self.regfile[register] = *value;
+      }
     }
 
-    // Return value and termination signal
-    let ret: Arc<Mutex<u16>> = Arc::new(Mutex::new(0));
+    // Termination signal
     let finished: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
     
     // Runs emulator on thread because graphics must use main thread
     let handle = thread::spawn({
-      let ret_clone = Arc::clone(&ret);
       let finished_clone = Arc::clone(&finished);
       move || {
-        while !self.halted {
-          let instruction = self.memory.read(usize::from(self.pc));
-          self.execute(instruction);
-          self.cycle_count += 1;
-        }
-        // return the value in r3
-        *ret_clone.lock().unwrap() = self.regfile[3];
+        let start = Instant::now();
+        self.stack_base = self.regfile[1];
+        let reason = loop {
+          if self.halted {
+            // return the value in r3
+            break StopReason::Halted(self.regfile[3]);
+          }
+          if let Some(reason) = self.check_limits(start) {
+            break reason;
+          }
+          throttle.pace(self.cycle_count);
+          self.check_interrupts();
+          let pc = self.pc;
+          let instruction = self.memory.fetch(usize::from(pc));
+          if let Some(reason) = self.step(pc, instruction) {
+            break reason;
+          }
+
+          if self.is_idle(pc, instruction) {
+            match &wakeup {
+              Some(wakeup) => self.sleep_until_wakeup(wakeup, start, &throttle),
+              // wait for an interrupt by running into it
+              None if self.interrupts.any_enabled() => {},
+              // nothing can ever arrive without a window
+              None => break StopReason::Idle(self.stop_state()),
+            }
+          }
+        };
         *finished_clone.lock().unwrap() = true;
         println!("{}", self.cycle_count);
+        if self.stack_depth > 0 {
+          println!("stack high-water mark: {} words, lowest sp {:#06x}",
+            self.stack_depth, self.stack_base.wrapping_sub(self.stack_depth));
+        }
+        self.write_reports();
+        reason
       }
     });
 
     if with_graphics {
       graphics.unwrap().start(finished, false);
     }
-    handle.join().unwrap();
 
-    return *ret.lock().unwrap();
+    handle.join().unwrap()
+  }
+
+  // executes the instruction at pc and does all the bookkeeping around it
+  fn step(&mut self, pc: u16, instruction: u16) -> Option<StopReason> {
+    self.record_trace(instruction);
+    let address = self.memory_operand(instruction);
+    if let Some(reason) = self.check_stack_guard(instruction, address) {
+      return Some(reason);
+    }
+    if let Some(address) = address.filter(|&address| instruction >> 13 == 4 && self.memory.is_write_protected(usize::from(address))) {
+      return Some(StopReason::ProtectionFault(address, self.stop_state()));
+    }
+    // a transfer that finished since the last instruction, while it or a sleep ticked the memory
+    if let Some(address) = self.memory.take_dma_fault() {
+      return Some(StopReason::ProtectionFault(address, self.stop_state()));
+    }
+    if let Some(modified) = self.memory.take_modified_fetch().filter(|&modified| self.warned_smc.insert(modified)) {
+      eprintln!("warning: executing {}, which a store modified after load", self.symbols.describe(modified));
+    }
+
+    self.execute(instruction);
+    let taken = self.pc != pc.wrapping_add(1);
+    let cycles = self.timing.cycles(instruction, pc, address, taken);
+    self.cycle_count += cycles;
+    self.memory.tick(cycles);
+
+    if let Some(pipeline) = &mut self.pipeline {
+      pipeline.issue(pc, instruction, taken);
+    }
+    if let Some(profiler) = &mut self.profiler {
+      profiler.record(pc, instruction, cycles, self.pc);
+    }
+    if let Some(coverage) = &mut self.coverage {
+      coverage.record(pc, instruction, taken);
+    }
+    self.track_stack_depth();
+    if let Some(hit) = self.memory.take_watch_hit() {
+      return Some(StopReason::Watchpoint(hit, StopState { pc, ..self.stop_state() }));
+    }
+    if let Some(address) = self.memory.take_uninitialized_read() {
+      if self.sanitizer == Some(Sanitizer::Trap) {
+        return Some(StopReason::UninitializedRead(address, StopState { pc, ..self.stop_state() }));
+      }
+      if self.warned.insert(pc) {
+        eprintln!("warning: {} reads uninitialized memory at {:#06x}", self.symbols.describe(pc), address);
+      }
+    }
+    None
+  }
+
+  // stops a store through the stack pointer below the stack limit before it happens
+  fn check_stack_guard(&self, instr: u16, address: Option<u16>) -> Option<StopReason> {
+    let limit = self.stack_limit?;
+    let address = address?;
+    let through_sp = instr >> 13 == 4 && (instr >> 7) & 0b111 == 1;
+    if through_sp && address < limit {
+      return Some(StopReason::StackOverflow(address, self.stop_state()));
+    }
+    None
+  }
+
+  fn track_stack_depth(&mut self) {
+    // the stack grows down from wherever r1 started, which wraps
+    // around to the top of memory when it started at 0
+    let depth = self.stack_base.wrapping_sub(self.regfile[1]);
+    if depth < 0x8000 && depth > self.stack_depth {
+      self.stack_depth = depth;
+    }
+  }
+
+  fn write_reports(&self) {
+    if let Some(Err(e)) = self.pipeline.as_ref().map(|pipeline| pipeline.write_report(&self.symbols)) {
+      eprintln!("Failed to write pipeline report: {}", e);
+    }
+    if let Some(Err(e)) = self.profiler.as_ref().map(|profiler| profiler.write_report(&self.symbols)) {
+      eprintln!("Failed to write profile: {}", e);
+    }
+    let program_size = self.memory.program_size();
+    if let Some(Err(e)) = self.coverage.as_ref().map(|coverage| coverage.write_report(&self.symbols, program_size)) {
+      eprintln!("Failed to write coverage report: {}", e);
+    }
+    if let Some(Err(e)) = self.views_dir.as_ref().map(|dir| viewers::export(&self.memory.get_video_memory(), dir)) {
+      eprintln!("Failed to export views: {}", e);
+    }
+  }
+
+  fn check_limits(&mut self, start: Instant) -> Option<StopReason> {
+    if self.max_cycles.is_some_and(|max_cycles| self.cycle_count >= max_cycles) {
+      return Some(StopReason::CycleLimit(self.stop_state()));
+    }
+    // instructions and sleeps can add many cycles at once, so this can't wait for an exact multiple
+    if self.timeout.is_some() && self.cycle_count >= self.next_timeout_check {
+      self.next_timeout_check = self.cycle_count + TIMEOUT_CHECK_INTERVAL;
+      if self.timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+        return Some(StopReason::Timeout(self.stop_state()));
+      }
+    }
+    None
+  }
+
+  // the address a load or store is about to access
+  fn memory_operand(&self, instr: u16) -> Option<u16> {
+    match instr >> 13 {
+      4 | 5 => {
+        let r_b = (instr >> 7) & 0b111;
+        let imm = Self::sign_ext_7(instr & 0x7F);
+        Some(u16::wrapping_add(self.regfile[usize::from(r_b)], imm))
+      },
+      _ => None,
+    }
+  }
+
+  // a taken branch back to itself never ends and an empty
+  // input port that is polled over and over stays empty
+  fn is_idle(&self, pc: u16, instr: u16) -> bool {
+    let self_branch = instr >> 13 == 6 && self.pc == pc;
+    self_branch || self.memory.is_polling_idle()
+  }
+
+  fn sleep_until_wakeup(&mut self, wakeup: &Wakeup, start: Instant, throttle: &Throttle) {
+    // the program would have kept spinning while we sleep, so credit it with the
+    // cycles it would have run at the paced clock or the rate seen so far
+    let rate = throttle.paced_hz()
+      .unwrap_or(self.cycle_count as f64 / start.elapsed().as_secs_f64());
+    let slept = wakeup.wait(IDLE_SLEEP);
+    let mut skipped = (slept.as_secs_f64() * rate) as u64;
+    if let Some(max_cycles) = self.max_cycles {
+      skipped = skipped.min(max_cycles.saturating_sub(self.cycle_count));
+    }
+    self.cycle_count += skipped;
+    self.memory.tick(skipped);
+  }
+
+  // enters the handler of an interrupt that is pending and enabled,
+  // unless the program is already in one
+  fn check_interrupts(&mut self) {
+    if self.interrupt_return.is_none() && self.interrupts.active() != 0 {
+      self.interrupt_return = Some((self.pc, self.flags));
+      self.pc = self.interrupts.vector();
+    }
+  }
+
+  fn record_trace(&mut self, instr: u16) {
+    if self.trace.len() == TRACE_LENGTH {
+      self.trace.pop_front();
+    }
+    self.trace.push_back(TraceEntry { pc: self.pc, instr });
+  }
+
+  fn stop_state(&self) -> StopState {
+    StopState {
+      pc: self.pc,
+      cycle_count: self.cycle_count,
+      trace: self.trace.iter().copied().collect(),
+      symbols: Arc::clone(&self.symbols),
+    }
   }
 
   fn execute(&mut self, instr : u16) {
@@ -96,7 +504,7 @@ impl Emulator {
     match opcode {
       0 => self.alu_op(args),
       1 => self.add_immediate(args),
-      2 => panic!("Invalid opcode"),
+      2 => panic!("Invalid opcode at {}", self.symbols.describe(self.pc)),
       3 => self.load_upper_immediate(args),
       4 => self.store_word(args),
       5 => self.load_word(args),
@@ -352,7 +760,15 @@ impl Emulator {
           print!("{}", character);
           self.pc += 1;
         },
-        _ => panic!("Invalid Exception code {exc_code:#x} from {args:#x}")
+        0x72 => {
+          // this is a sys RETI
+          // go back to where the interrupt came in
+          let (pc, flags) = self.interrupt_return.take()
+            .unwrap_or_else(|| panic!("sys RETI outside an interrupt handler at {}", self.symbols.describe(self.pc)));
+          self.pc = pc;
+          self.flags = flags;
+        },
+        _ => panic!("Invalid Exception code {exc_code:#x} from {args:#x} at {}", self.symbols.describe(self.pc))
       }
     } else {
       // this is a jalr
@@ -396,4 +812,77 @@ impl Emulator {
       x
     }
   }
-}
\ No newline at end of file
+}
+
+impl Wakeup {
+  pub fn notify(&self) {
+    *self.pending.lock().unwrap() = true;
+    self.signal.notify_one();
+  }
+
+  // blocks until notified or the timeout runs out, returns the time slept
+  fn wait(&self, timeout: Duration) -> Duration {
+    let start = Instant::now();
+    let pending = self.pending.lock().unwrap();
+    let (mut pending, _) = self.signal.wait_timeout_while(pending, timeout, |pending| !*pending).unwrap();
+    *pending = false;
+    start.elapsed()
+  }
+}
+
+impl fmt::Display for StopReason {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      StopReason::Halted(result) => write!(f, "halted with {}", result),
+      StopReason::CycleLimit(state) => {
+        writeln!(f, "cycle limit reached at {} after {} cycles", state.location(), state.cycle_count)?;
+        state.fmt(f)
+      },
+      StopReason::Timeout(state) => {
+        writeln!(f, "timed out at {} after {} cycles", state.location(), state.cycle_count)?;
+        state.fmt(f)
+      },
+      StopReason::Watchpoint(hit, state) => {
+        let access = match hit.kind {
+          WatchKind::Read => format!("read {:#06x} from", hit.new),
+          WatchKind::Write => format!("wrote {:#06x} to", hit.new),
+          WatchKind::Change => format!("changed {:#06x} to {:#06x} at", hit.old, hit.new),
+        };
+        writeln!(f, "watchpoint: {} {} {:#06x}", state.location(), access, hit.address)?;
+        state.fmt(f)
+      },
+      StopReason::StackOverflow(address, state) => {
+        writeln!(f, "stack overflow: {} stores to {:#06x}, below the stack limit", state.location(), address)?;
+        state.fmt(f)
+      },
+      StopReason::UninitializedRead(address, state) => {
+        writeln!(f, "uninitialized read: {} reads {:#06x}, which nothing has written", state.location(), address)?;
+        state.fmt(f)
+      },
+      StopReason::ProtectionFault(address, state) => {
+        writeln!(f, "protection fault: {} stores to {:#06x}, inside the program", state.location(), address)?;
+        state.fmt(f)
+      },
+      StopReason::Idle(state) => {
+        writeln!(f, "program idle at {} after {} cycles", state.location(), state.cycle_count)?;
+        state.fmt(f)
+      },
+    }
+  }
+}
+
+impl StopState {
+  pub fn location(&self) -> String {
+    self.symbols.describe(self.pc)
+  }
+}
+
+impl fmt::Display for StopState {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "last {} instructions:", self.trace.len())?;
+    for entry in &self.trace {
+      write!(f, "\n  {:#06x}  {}", entry.instr, self.symbols.describe(entry.pc))?;
+    }
+    Ok(())
+  }
+}
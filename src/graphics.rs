@@ -1,36 +1,51 @@
 use piston_window::*;
-use ::image::{ImageBuffer, Rgba};
-use std::{collections::VecDeque, sync::{Arc, Mutex, RwLock}};
+use std::{collections::VecDeque, sync::{Arc, Mutex, RwLock}, time::{Duration, Instant}};
 
 use crate::memory::*;
-
-const SCREEN_WIDTH: u32 = 640;
-const SCREEN_HEIGHT: u32 = 480;
+use crate::emulator::Wakeup;
+use crate::clock::{SpeedControl, SpeedMode};
+use crate::video::{Frame, VideoControl, VideoMemory, SCREEN_WIDTH, SCREEN_HEIGHT};
+use crate::viewers;
+const TITLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// speed hotkeys, these are not passed on to the program
+const PAUSE_KEY: Key = Key::F5;
+const SLOW_MOTION_KEY: Key = Key::F6;
+const TURBO_KEY: Key = Key::F7;
+// debug viewer hotkeys, also not passed on
+const TILES_KEY: Key = Key::F1;
+const SPRITE_BOXES_KEY: Key = Key::F2;
+const TILE_MAP_KEY: Key = Key::F3;
+
+// what the window shows in place of the screen
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum View {
+    Screen,
+    Tiles,
+    TileMap,
+}
 
 pub struct Graphics {
     window: PistonWindow,
-    buffer: ImageBuffer<Rgba<u8>, Vec<u8>>,
     texture: G2dTexture,
-    frame_buffer: Arc<RwLock<FrameBuffer>>,
-    tile_map: Arc<RwLock<TileMap>>,
+    frame: Arc<RwLock<Frame>>,
+    frame_shown: u64, // number of the frame in the texture
+    video: Arc<VideoControl>,
+    video_memory: VideoMemory,
+    view: View,
+    view_size: (u32, u32), // of the image in the texture
+    sprite_boxes: bool, // outline the sprites on the screen
+    redraw: bool, // the view changed since the texture was made
     io_buffer: Arc<RwLock<VecDeque<u16>>>,
-    vscroll_register: Arc<RwLock<u16>>,
-    hscroll_register: Arc<RwLock<u16>>,
-    scale_register: Arc<RwLock<u16>>,
-    sprite_map: Arc<RwLock<SpriteMap>>,
+    wakeup: Arc<Wakeup>,
+    speed: Arc<RwLock<SpeedControl>>,
+    title_updated: Instant,
 }
 
 impl Graphics {
 
-    pub fn new(
-        frame_buffer: Arc<RwLock<FrameBuffer>>, 
-        tile_map: Arc<RwLock<TileMap>>, 
-        io_buffer: Arc<RwLock<VecDeque<u16>>>, 
-        vscroll_register: Arc<RwLock<u16>>,
-        hscroll_register: Arc<RwLock<u16>>,
-        sprite_map: Arc<RwLock<SpriteMap>>,
-        scale_register: Arc<RwLock<u16>>,
-    ) -> Graphics {
+    // shares the video memory and input port of the emulated machine
+    pub fn new(memory: &Memory, wakeup: Arc<Wakeup>, speed: Arc<RwLock<SpeedControl>>) -> Graphics {
         let mut window: PistonWindow = WindowSettings::new("JPEB", [SCREEN_WIDTH, SCREEN_HEIGHT])
             .exit_on_esc(true)
             .build()
@@ -38,24 +53,28 @@ impl Graphics {
         window.set_max_fps(60);
         window.set_ups(60);
 
-        let buffer: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(FRAME_WIDTH, FRAME_HEIGHT);
+        let frame = memory.get_frame();
         let texture = Texture::from_image(
             &mut window.create_texture_context(),
-            &buffer,
+            &frame.read().unwrap().pixels,
             &TextureSettings::new(),
         ).unwrap();
 
         Graphics { 
             window,
-            buffer,
             texture,
-            frame_buffer,
-            tile_map,
-            io_buffer,
-            vscroll_register,
-            hscroll_register,
-            sprite_map,
-            scale_register,
+            frame,
+            frame_shown: 0,
+            video: memory.get_video_control(),
+            video_memory: memory.get_video_memory(),
+            view: View::Screen,
+            view_size: (SCREEN_WIDTH, SCREEN_HEIGHT),
+            sprite_boxes: false,
+            redraw: false,
+            io_buffer: memory.get_io_buffer(),
+            wakeup,
+            speed,
+            title_updated: Instant::now(),
         }
     }
     
@@ -68,20 +87,41 @@ impl Graphics {
                     if !stay_open && *finished.lock().unwrap() {
                         self.window.set_should_close(true);
                     }
+                    self.video.request_frame();
                     self.update();
+                    self.wakeup.notify();
+                    if self.title_updated.elapsed() >= TITLE_INTERVAL {
+                        self.update_title();
+                    }
                 }
                 Event::Loop(Loop::Render(_args)) => {
                     self.window.draw_2d(&event, |context, graphics, _| {
                         clear([0.0; 4], graphics); // black background
-                        image(&self.texture, context.transform, graphics);
+                        // debug views are shrunk or stretched to fit the window
+                        let (width, height) = self.view_size;
+                        let fit = match self.view {
+                            View::Screen => 1.0,
+                            _ => f64::min(f64::from(SCREEN_WIDTH) / f64::from(width), f64::from(SCREEN_HEIGHT) / f64::from(height)),
+                        };
+                        image(&self.texture, context.transform.scale(fit, fit), graphics);
                     });
                 }
                 Event::Input(Input::Button(ButtonArgs { 
                     button: Button::Keyboard(key), 
                     state, .. }), _) => {
                     match state {
+                        ButtonState::Press if key == PAUSE_KEY => self.toggle_speed(SpeedMode::Paused),
+                        ButtonState::Press if key == SLOW_MOTION_KEY => self.toggle_speed(SpeedMode::SlowMotion),
+                        ButtonState::Press if key == TURBO_KEY => self.toggle_speed(SpeedMode::Turbo),
+                        ButtonState::Press if key == TILES_KEY => self.toggle_view(View::Tiles),
+                        ButtonState::Press if key == TILE_MAP_KEY => self.toggle_view(View::TileMap),
+                        ButtonState::Press if key == SPRITE_BOXES_KEY => {
+                            self.sprite_boxes = !self.sprite_boxes;
+                            self.redraw = true;
+                        }
                         ButtonState::Press => {
                             self.io_buffer.write().unwrap().push_back(key as u16);
+                            self.wakeup.notify();
                             // println!("Key pressed: {:?}", key);
                             // Handle key press here
                         }
@@ -94,88 +134,71 @@ impl Graphics {
                 _ => {}
             }
         }
+        // nothing could unpause the emulator once the window is gone
+        let mut speed = self.speed.write().unwrap();
+        if speed.mode == SpeedMode::Paused {
+            speed.mode = SpeedMode::Normal;
+        }
     }
 
 
-    fn update(&mut self) {
-        // Updates buffer from emulated frame buffer and tile map
-        // draw the tiles of the frame buffer
-        let fb = self.frame_buffer.read().unwrap();
-        let tile_map = self.tile_map.read().unwrap();
-        let scale = 1 << (*self.scale_register.read().unwrap() as u32);
-        for x in 0..fb.width {
-            for y in 0..fb.height {
-                let tile_ptr = fb.get_tile(x, y);
-                let tile = &tile_map.tiles[tile_ptr as usize];
-                for px in 0..TILE_SIZE {
-                    for py in 0..TILE_SIZE {
-                        let tile_pixel: u16 = tile.pixels[(px + py * TILE_SIZE) as usize];
-                        let red = (tile_pixel & 0x000f) as u8 * 16;
-                        let green = ((tile_pixel & 0x00f0) >> 4) as u8 * 16;
-                        let blue = ((tile_pixel & 0x0f00) >> 8) as u8 * 16;
-                        let pixel = Rgba([red, green, blue, 255]);
-                        
-                        // positions in the logical screen
-                        let scroll_x = *self.hscroll_register.read().unwrap() as i32;
-                        let scroll_y = *self.vscroll_register.read().unwrap() as i32;
-                        let raw_x: i32 = (x * TILE_SIZE) as i32 + px as i32 + scroll_x;
-                        let raw_y: i32 = (y * TILE_SIZE) as i32 + py as i32 + scroll_y;
-                        let final_x: u32 = (raw_x + FRAME_WIDTH as i32) as u32 % FRAME_WIDTH;
-                        let final_y: u32 = (raw_y + FRAME_HEIGHT as i32) as u32 % FRAME_HEIGHT;
-
-                        // print the pixel rgba in the physical screen
-                        for i in 0..scale {
-                            for j in 0..scale {
-                                let screen_x: u32 = final_x * scale + i;
-                                let screen_y: u32 = final_y * scale + j;
-
-                                if screen_x < SCREEN_WIDTH && screen_y < SCREEN_HEIGHT {
-                                    self.buffer.put_pixel(screen_x, screen_y, pixel);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-
-        // draw the sprites of the sprite map
-        let sprite_map = self.sprite_map.read().unwrap();
-        for sprite in &sprite_map.sprites {
-            for px in 0..SPRITE_SIZE {
-                for py in 0..SPRITE_SIZE {
-                    let tile_pixel: u16 = sprite.pixels[(px + py * SPRITE_SIZE) as usize];
-                    let red = (tile_pixel & 0x000f) as u8 * 16;
-                    let green = ((tile_pixel & 0x00f0) >> 4) as u8 * 16;
-                    let blue = ((tile_pixel & 0x0f00) >> 8) as u8 * 16;
-                    let transparent = (tile_pixel & 0xf000) == 0xf000;
-                    if transparent {
-                        continue;
-                    }
+    fn toggle_speed(&mut self, mode: SpeedMode) {
+        self.speed.write().unwrap().toggle(mode);
+        self.update_title();
+    }
 
-                    let pixel = Rgba([red, green, blue, 255]);
-                    let final_x: u32 = sprite.x as u32 + px;
-                    let final_y: u32 = sprite.y as u32 + py;
+    // switches between a debug view and the screen
+    fn toggle_view(&mut self, view: View) {
+        self.view = if self.view == view { View::Screen } else { view };
+        self.redraw = true;
+    }
 
-                    // print the pixel rgba in the physical screen
-                    for i in 0..scale {
-                        for j in 0..scale {
-                            let screen_x: u32 = final_x * scale + i;
-                            let screen_y: u32 = final_y * scale + j;
+    // shows the effective clock speed of the emulator in the title bar
+    fn update_title(&mut self) {
+        let speed = self.speed.read().unwrap();
+        let mode = match speed.mode {
+            SpeedMode::Normal => "",
+            SpeedMode::Turbo => " [turbo]",
+            SpeedMode::Paused => " [paused]",
+            SpeedMode::SlowMotion => " [slow motion]",
+        };
+        let title = format!("JPEB - {:.2} MHz{}", speed.effective_hz / 1_000_000.0, mode);
+        drop(speed);
+        self.window.set_title(title);
+        self.title_updated = Instant::now();
+    }
 
-                            if screen_x < SCREEN_WIDTH && screen_y < SCREEN_HEIGHT {
-                                self.buffer.put_pixel(screen_x, screen_y, pixel);
-                            }
-                        }
-                    }
+    // shows the last frame the emulator rendered, or the debug view the
+    // keys picked, which is redrawn every update to follow the program
+    fn update(&mut self) {
+        match self.view {
+            View::Tiles => self.show(&viewers::tile_sheet(&self.video_memory)),
+            View::TileMap => self.show(&viewers::tile_map(&self.video_memory)),
+            View::Screen => {
+                let frame = Arc::clone(&self.frame);
+                let frame = frame.read().unwrap();
+                if frame.number == self.frame_shown && !self.redraw {
+                    return;
+                }
+                self.frame_shown = frame.number;
+                if self.sprite_boxes {
+                    let mut pixels = frame.pixels.clone();
+                    viewers::draw_sprite_boxes(&mut pixels, &self.video_memory);
+                    self.show(&pixels);
+                } else {
+                    self.show(&frame.pixels);
                 }
             }
         }
+    }
 
-        // Updates texture from buffer
+    // Updates texture from an image
+    fn show(&mut self, view: &viewers::View) {
+        self.redraw = false;
+        self.view_size = view.dimensions();
         self.texture = Texture::from_image(
             &mut self.window.create_texture_context(),
-            &self.buffer,
+            view,
             &TextureSettings::new(),
         ).unwrap();
     }
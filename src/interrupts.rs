@@ -0,0 +1,78 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use crate::bus::Device;
+
+// bits of the enable and pending registers
+pub const IRQ_VBLANK : u16 = 1 << 0;
+pub const IRQ_LINE : u16 = 1 << 1; // line compare
+pub const IRQ_COLLISION : u16 = 1 << 2; // a sprite touched another sprite or the background
+pub const IRQ_DMA : u16 = 1 << 3;       // a dma transfer completed
+
+// Interrupt lines raised by devices and taken by the cpu. A device sets
+// its bit in pending; if that bit is also enabled, the cpu saves the pc and
+// flags and jumps to the vector, and sys RETI returns from the handler.
+// Handlers acknowledge an interrupt by writing its bit to pending.
+#[derive(Default)]
+pub struct Interrupts {
+    enable: AtomicU16,
+    pending: AtomicU16,
+    vector: AtomicU16,
+}
+
+// the enable, pending and vector registers
+pub struct InterruptController {
+    interrupts: Arc<Interrupts>,
+}
+
+impl Interrupts {
+    pub fn raise(&self, irq: u16) {
+        self.pending.fetch_or(irq, Ordering::Relaxed);
+    }
+
+    // interrupts that are both pending and enabled
+    pub fn active(&self) -> u16 {
+        self.pending.load(Ordering::Relaxed) & self.enable.load(Ordering::Relaxed)
+    }
+
+    pub fn any_enabled(&self) -> bool {
+        self.enable.load(Ordering::Relaxed) != 0
+    }
+
+    pub fn vector(&self) -> u16 {
+        self.vector.load(Ordering::Relaxed)
+    }
+}
+
+impl InterruptController {
+    pub fn new(interrupts: Arc<Interrupts>) -> InterruptController {
+        InterruptController { interrupts }
+    }
+}
+
+impl Device for InterruptController {
+    fn name(&self) -> &str {
+        "interrupt controller"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        match offset {
+            0 => self.interrupts.enable.load(Ordering::Relaxed),
+            1 => self.interrupts.pending.load(Ordering::Relaxed),
+            _ => self.interrupts.vector(),
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        match offset {
+            0 => self.interrupts.enable.store(data, Ordering::Relaxed),
+            // writing a bit clears it
+            1 => { self.interrupts.pending.fetch_and(!data, Ordering::Relaxed); },
+            _ => self.interrupts.vector.store(data, Ordering::Relaxed),
+        }
+    }
+}
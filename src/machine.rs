@@ -0,0 +1,311 @@
+use std::ops::RangeInclusive;
+
+use crate::config::{self, Config, Entry};
+use crate::memory::{STACK_START, TILE_MAP_START, TILE_MAP_SIZE, FRAME_BUFFER_START, FRAME_BUFFER_SIZE,
+    SPRITE_MAP_START, SPRITE_MAP_SIZE, SPRITE_REGISTERS_START, SPIRTE_REGISTERS_SIZE,
+    V_SCROLL_START, H_SCROLL_START, SCALE_REGISTER_START, PS2_STREAM, UART_TX,
+    INTERRUPTS_START, INTERRUPTS_SIZE, VIDEO_TIMING_START, VIDEO_TIMING_SIZE,
+    PALETTE_MODES_START, PALETTE_MODES_SIZE, PALETTE_START, PALETTE_SIZE,
+    TILE_ATTRIBUTES_START, TILE_ATTRIBUTES_SIZE, SPRITE_TABLE_START, SPRITE_TABLE_SIZE,
+    COLLISIONS_START, COLLISIONS_SIZE, VIDEO_MODE_START, BITMAP_BANK_START, BITMAP_WINDOW_START, BITMAP_WINDOW_SIZE,
+    TEXT_START, TEXT_SIZE, DMA_START, DMA_SIZE};
+
+// The devices of a board revision, where they are mapped, the files the
+// memory is initialized from and the registers the program starts with.
+// The standard machine is the one the constants in memory.rs describe, and
+// a machine file changes whatever it mentions of it:
+//   [data]
+//   mem_hex = "boards/rev2/mem.hex"
+//   [devices]               # once present, only the devices listed exist
+//   tile_map = "0x8000-0x9FFF"
+//   frame_buffer = 0xE000   # a start address keeps the standard size
+//   uart = 0xF000
+//   [write_through]         # stores to these devices reach the ram behind them too
+//   frame_buffer = true
+//   [palette]               # quantize the bitmaps and start in indexed mode
+//   tiles = true
+//   [registers]
+//   r1 = 0x7FFF
+//   pc = 0x0010
+pub struct Machine {
+    pub devices: Vec<(DeviceKind, RangeInclusive<u16>)>,
+    pub write_through: Vec<DeviceKind>,
+    pub mem_hex: String,
+    pub tile_map: String,
+    pub sprite_map: String,
+    pub indexed_tiles: bool,
+    pub indexed_sprites: bool,
+    pub stack_start: u16,              // where r1 and r2 start with a window
+    pub registers: [Option<u16>; 8],   // overrides, after stack_start
+    pub pc: u16,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeviceKind {
+    TileMap,
+    FrameBuffer,
+    SpriteMap,
+    SpriteRegisters,
+    VScroll,
+    HScroll,
+    Scale,
+    Ps2,
+    Uart,
+    Interrupts,
+    VideoTiming,
+    PaletteModes,
+    Palette,
+    TileAttributes,
+    SpriteTable,
+    Collisions,
+    VideoMode,
+    BitmapBank,
+    Bitmap,
+    Text,
+    Dma,
+}
+
+const DEVICE_KINDS : [DeviceKind; 21] = [
+    DeviceKind::TileMap,
+    DeviceKind::FrameBuffer,
+    DeviceKind::SpriteMap,
+    DeviceKind::SpriteRegisters,
+    DeviceKind::VScroll,
+    DeviceKind::HScroll,
+    DeviceKind::Scale,
+    DeviceKind::Ps2,
+    DeviceKind::Uart,
+    DeviceKind::Interrupts,
+    DeviceKind::VideoTiming,
+    DeviceKind::PaletteModes,
+    DeviceKind::Palette,
+    DeviceKind::TileAttributes,
+    DeviceKind::SpriteTable,
+    DeviceKind::Collisions,
+    DeviceKind::VideoMode,
+    DeviceKind::BitmapBank,
+    DeviceKind::Bitmap,
+    DeviceKind::Text,
+    DeviceKind::Dma,
+];
+
+impl DeviceKind {
+    // the key of the device in a machine file
+    pub fn key(self) -> &'static str {
+        match self {
+            DeviceKind::TileMap => "tile_map",
+            DeviceKind::FrameBuffer => "frame_buffer",
+            DeviceKind::SpriteMap => "sprite_map",
+            DeviceKind::SpriteRegisters => "sprite_registers",
+            DeviceKind::VScroll => "vscroll",
+            DeviceKind::HScroll => "hscroll",
+            DeviceKind::Scale => "scale",
+            DeviceKind::Ps2 => "ps2",
+            DeviceKind::Uart => "uart",
+            DeviceKind::Interrupts => "interrupts",
+            DeviceKind::VideoTiming => "video_timing",
+            DeviceKind::PaletteModes => "palette_modes",
+            DeviceKind::Palette => "palette",
+            DeviceKind::TileAttributes => "tile_attributes",
+            DeviceKind::SpriteTable => "sprite_table",
+            DeviceKind::Collisions => "collisions",
+            DeviceKind::VideoMode => "video_mode",
+            DeviceKind::BitmapBank => "bitmap_bank",
+            DeviceKind::Bitmap => "bitmap",
+            DeviceKind::Text => "text",
+            DeviceKind::Dma => "dma",
+        }
+    }
+
+    // where the standard machine maps the device
+    fn standard_start(self) -> usize {
+        match self {
+            DeviceKind::TileMap => TILE_MAP_START,
+            DeviceKind::FrameBuffer => FRAME_BUFFER_START,
+            DeviceKind::SpriteMap => SPRITE_MAP_START,
+            DeviceKind::SpriteRegisters => SPRITE_REGISTERS_START,
+            DeviceKind::VScroll => V_SCROLL_START,
+            DeviceKind::HScroll => H_SCROLL_START,
+            DeviceKind::Scale => SCALE_REGISTER_START,
+            DeviceKind::Ps2 => PS2_STREAM,
+            DeviceKind::Uart => UART_TX,
+            DeviceKind::Interrupts => INTERRUPTS_START,
+            DeviceKind::VideoTiming => VIDEO_TIMING_START,
+            DeviceKind::PaletteModes => PALETTE_MODES_START,
+            DeviceKind::Palette => PALETTE_START,
+            DeviceKind::TileAttributes => TILE_ATTRIBUTES_START,
+            DeviceKind::SpriteTable => SPRITE_TABLE_START,
+            DeviceKind::Collisions => COLLISIONS_START,
+            DeviceKind::VideoMode => VIDEO_MODE_START,
+            DeviceKind::BitmapBank => BITMAP_BANK_START,
+            DeviceKind::Bitmap => BITMAP_WINDOW_START,
+            DeviceKind::Text => TEXT_START,
+            DeviceKind::Dma => DMA_START,
+        }
+    }
+
+    // the most words the device has behind it
+    pub fn size(self) -> usize {
+        match self {
+            DeviceKind::TileMap => TILE_MAP_SIZE,
+            DeviceKind::FrameBuffer => FRAME_BUFFER_SIZE,
+            DeviceKind::SpriteMap => SPRITE_MAP_SIZE,
+            DeviceKind::SpriteRegisters => SPIRTE_REGISTERS_SIZE,
+            DeviceKind::Interrupts => INTERRUPTS_SIZE,
+            DeviceKind::VideoTiming => VIDEO_TIMING_SIZE,
+            DeviceKind::PaletteModes => PALETTE_MODES_SIZE,
+            DeviceKind::Palette => PALETTE_SIZE,
+            DeviceKind::TileAttributes => TILE_ATTRIBUTES_SIZE,
+            DeviceKind::SpriteTable => SPRITE_TABLE_SIZE,
+            DeviceKind::Collisions => COLLISIONS_SIZE,
+            DeviceKind::Bitmap => BITMAP_WINDOW_SIZE,
+            DeviceKind::Text => TEXT_SIZE,
+            DeviceKind::Dma => DMA_SIZE,
+            _ => 1,
+        }
+    }
+}
+
+impl Machine {
+    pub fn standard(datapath: &str) -> Machine {
+        Machine {
+            devices: DEVICE_KINDS.iter().map(|&kind| (kind, span(kind.standard_start(), kind.size()))).collect(),
+            write_through: vec![],
+            mem_hex: format!("{datapath}/mem.hex"),
+            tile_map: format!("{datapath}/tilemap.bmp"),
+            sprite_map: format!("{datapath}/spritemap.bmp"),
+            indexed_tiles: false,
+            indexed_sprites: false,
+            stack_start: STACK_START as u16,
+            registers: [None; 8],
+            pc: 0,
+        }
+    }
+
+    // the standard machine with the changes a machine file makes to it
+    pub fn load(path: &str, datapath: &str) -> Result<Machine, String> {
+        let config = Config::load(path)?;
+        let mut machine = Machine::standard(datapath);
+        machine.configure(&config).map_err(|e| format!("{path}: {e}"))?;
+        Ok(machine)
+    }
+
+    pub fn configure(&mut self, config: &Config) -> Result<(), String> {
+        for section in &config.sections {
+            match section.name.as_str() {
+                "data" => self.configure_data(&section.entries)?,
+                "devices" => self.configure_devices(&section.entries)?,
+                "write_through" => self.configure_write_through(&section.entries)?,
+                "registers" => self.configure_registers(&section.entries)?,
+                "palette" => self.configure_palette(&section.entries)?,
+                "" if section.entries.is_empty() => {}
+                name => return Err(format!("unknown section [{name}]")),
+            }
+        }
+        Ok(())
+    }
+
+    fn configure_data(&mut self, entries: &[Entry]) -> Result<(), String> {
+        for entry in entries {
+            let path = match entry.key.as_str() {
+                "mem_hex" => &mut self.mem_hex,
+                "tile_map" => &mut self.tile_map,
+                "sprite_map" => &mut self.sprite_map,
+                _ => return Err(entry.error("unknown data file")),
+            };
+            *path = entry.as_str()?.to_string();
+        }
+        Ok(())
+    }
+
+    fn configure_devices(&mut self, entries: &[Entry]) -> Result<(), String> {
+        self.devices.clear();
+        for entry in entries {
+            let kind = DEVICE_KINDS.iter().copied().find(|kind| kind.key() == entry.key)
+                .ok_or(entry.error("unknown device"))?;
+            let range = match entry.as_str() {
+                Ok(range) => config::parse_range(range).ok_or(entry.error("expected an address range like 0xC000-0xDFFF"))?,
+                Err(_) => span(usize::from(entry.as_u16()?), kind.size()),
+            };
+            if range.len() > kind.size() {
+                return Err(entry.error(&format!("the device only has {:#x} words", kind.size())));
+            }
+            if let Some((other, _)) = self.devices.iter().find(|(_, mapped)| mapped.start() <= range.end() && range.start() <= mapped.end()) {
+                return Err(entry.error(&format!("overlaps {}", other.key())));
+            }
+            self.devices.push((kind, range));
+        }
+        Ok(())
+    }
+
+    fn configure_write_through(&mut self, entries: &[Entry]) -> Result<(), String> {
+        for entry in entries {
+            let kind = DEVICE_KINDS.iter().copied().find(|kind| kind.key() == entry.key)
+                .ok_or(entry.error("unknown device"))?;
+            self.write_through.retain(|&other| other != kind);
+            if entry.as_bool()? {
+                self.write_through.push(kind);
+            }
+        }
+        Ok(())
+    }
+
+    // every way the regions of memory collide, one per line
+    pub fn validate(&self, program_size: usize) -> Result<(), String> {
+        let mut problems: Vec<String> = vec![];
+        let describe = |kind: DeviceKind, range: &RangeInclusive<u16>| format!("{} at {:#06x}-{:#06x}", kind.key(), range.start(), range.end());
+        for (i, (kind, range)) in self.devices.iter().enumerate() {
+            for (other, other_range) in &self.devices[i + 1..] {
+                if range.start() <= other_range.end() && other_range.start() <= range.end() {
+                    problems.push(format!("{} overlaps {}", describe(*kind, range), describe(*other, other_range)));
+                }
+            }
+            if program_size > usize::from(*range.start()) {
+                problems.push(format!("the program at 0x0000-{:#06x} overlaps {}", program_size - 1, describe(*kind, range)));
+            }
+            if range.contains(&self.stack_start) {
+                problems.push(format!("the stack starts at {:#06x}, inside {}", self.stack_start, describe(*kind, range)));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n"))
+        }
+    }
+
+    fn configure_palette(&mut self, entries: &[Entry]) -> Result<(), String> {
+        for entry in entries {
+            let indexed = match entry.key.as_str() {
+                "tiles" => &mut self.indexed_tiles,
+                "sprites" => &mut self.indexed_sprites,
+                _ => return Err(entry.error("expected tiles or sprites")),
+            };
+            *indexed = entry.as_bool()?;
+        }
+        Ok(())
+    }
+
+    fn configure_registers(&mut self, entries: &[Entry]) -> Result<(), String> {
+        for entry in entries {
+            let value = entry.as_u16()?;
+            match entry.key.as_str() {
+                "pc" => self.pc = value,
+                "stack_start" => self.stack_start = value,
+                key => {
+                    let register = key.strip_prefix('r')
+                        .and_then(|number| number.parse::<usize>().ok())
+                        .filter(|&number| (1..8).contains(&number))
+                        .ok_or(entry.error("expected r1 to r7, pc or stack_start"))?;
+                    self.registers[register] = Some(value);
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+// the addresses a device of this many words starting here takes up, cut off at the top of memory
+pub fn span(start: usize, size: usize) -> RangeInclusive<u16> {
+    start as u16..=(start + size - 1).min(0xFFFF) as u16
+}
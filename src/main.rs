@@ -1,29 +1,234 @@
 use std::env;
+use std::ops::RangeInclusive;
 use std::process;
+use std::time::Duration;
 
 pub mod graphics;
 pub mod emulator;
 pub mod memory;
+pub mod bus;
+pub mod devices;
+pub mod machine;
+pub mod interrupts;
+pub mod dma;
+pub mod video;
+pub mod font;
+pub mod viewers;
+pub mod clock;
+pub mod config;
+pub mod timing;
+pub mod pipeline;
+pub mod profiler;
+pub mod symbols;
+pub mod coverage;
 pub mod tests;
 
-use emulator::Emulator;
+use emulator::{Emulator, Sanitizer, StopReason};
+use timing::TimingModel;
+use pipeline::Pipeline;
+use profiler::Profiler;
+use symbols::SymbolTable;
+use coverage::Coverage;
+use machine::Machine;
+use memory::{Watchpoint, WatchKind};
+
+const USAGE: &str = "Usage: bemu file.bin [data/] [options]
+  --machine FILE                         devices, data files and registers of the board
+  --max-cycles N, --timeout SECONDS      stop a runaway program
+  --clock-hz N                           pace the program to a clock
+  --frame-cycles N                       cycles per video frame without a clock
+  --dma-cycles N                         cycles per word the dma engine moves
+  --timing FILE                          cycles per instruction class
+  --pipeline REPORT                      simulate the pipeline
+    --forwarding on|off, --branch-penalty N
+  --profile REPORT, --coverage REPORT    write reports when the program stops
+  --export-views DIR                     save the tiles, sprites and tile map as pngs when it stops
+  --symbols FILE, --source-map FILE      names and source lines for diagnostics
+  --watch r|w|c:ADDRESS[-ADDRESS]        stop on a read, write or change, repeatable
+  --stack-limit ADDRESS                  stop on a push below this address
+  --sanitize warn|trap                   catch reads of uninitialized memory
+  --protect-code on|off                  stop on a store into the program
+    --writable-code ADDRESS[-ADDRESS]    except here, repeatable
+  --detect-smc on|off                    warn when modified code runs
+  --console on|off                       start in text mode, showing the uart output";
 
 fn main() {
   let args = env::args().collect::<Vec<_>>();
 
+  // split the arguments into positional ones and --option value pairs
+  let mut positional: Vec<&str> = vec![];
+  let mut max_cycles: Option<u64> = None;
+  let mut timeout: Option<Duration> = None;
+  let mut clock_hz: Option<u64> = None;
+  let mut frame_cycles: Option<u64> = None;
+  let mut dma_cycles: Option<u64> = None;
+  let mut timing = TimingModel::default();
+  let mut pipeline_report: Option<&str> = None;
+  let mut forwarding = true;
+  let mut branch_penalty: u64 = 2;
+  let mut profile_report: Option<&str> = None;
+  let mut coverage_report: Option<&str> = None;
+  let mut views_dir: Option<&str> = None;
+  let mut symbols_path: Option<&str> = None;
+  let mut source_map_path: Option<&str> = None;
+  let mut watchpoints: Vec<Watchpoint> = vec![];
+  let mut stack_limit: Option<u16> = None;
+  let mut machine_path: Option<&str> = None;
+  let mut sanitizer: Option<Sanitizer> = None;
+  let mut protect_code = false;
+  let mut writable_code: Vec<RangeInclusive<u16>> = vec![];
+  let mut detect_smc = false;
+  let mut console = false;
+  let mut i = 1;
+  while i < args.len() {
+    let arg = args[i].as_str();
+    if !arg.starts_with("--") {
+      positional.push(arg);
+      i += 1;
+      continue;
+    }
+    let value = args.get(i + 1).unwrap_or_else(|| usage_error(&format!("missing value for {arg}")));
+    match arg {
+      "--machine" => machine_path = Some(value),
+      "--max-cycles" => max_cycles = Some(parse_option(arg, value)),
+      "--timeout" => timeout = Some(Duration::try_from_secs_f64(parse_option(arg, value)).unwrap_or_else(|_| usage_error(&format!("invalid value {value} for {arg}")))),
+      "--clock-hz" => clock_hz = Some(parse_nonzero(arg, value)),
+      "--frame-cycles" => frame_cycles = Some(parse_option(arg, value)),
+      "--dma-cycles" => dma_cycles = Some(parse_option(arg, value)),
+      "--timing" => timing = TimingModel::load(value).unwrap_or_else(|e| usage_error(&e)),
+      "--pipeline" => pipeline_report = Some(value),
+      "--forwarding" => forwarding = parse_switch(arg, value),
+      "--branch-penalty" => branch_penalty = parse_option(arg, value),
+      "--profile" => profile_report = Some(value),
+      "--coverage" => coverage_report = Some(value),
+      "--export-views" => views_dir = Some(value),
+      "--symbols" => symbols_path = Some(value),
+      "--source-map" => source_map_path = Some(value),
+      "--watch" => watchpoints.push(parse_watchpoint(value)),
+      "--stack-limit" => stack_limit = Some(parse_address(arg, value)),
+      "--sanitize" => sanitizer = Some(parse_sanitizer(value)),
+      "--protect-code" => protect_code = parse_switch(arg, value),
+      "--writable-code" => writable_code.push(config::parse_range(value).unwrap_or_else(|| usage_error(&format!("invalid address range {value}")))),
+      "--detect-smc" => detect_smc = parse_switch(arg, value),
+      "--console" => console = parse_switch(arg, value),
+      _ => usage_error(&format!("unknown option {arg}")),
+    }
+    i += 2;
+  }
+
   let mut datapath = "../data";
-  if args.len() > 2 {
-    datapath = &args[2];
+  if positional.len() > 1 {
+    datapath = positional[1];
   } 
-  if args.len() > 1 {
+  if !positional.is_empty() {
     // file to run is passed as a command line argument
-    let cpu = Emulator::new(&args[1], datapath);
-    let result = cpu.run(true);
-    println!("<< {} >>", result); // print a newline
-    // process::exit(i32::from(result));
-    process::exit(0);
+    let machine = match machine_path {
+      Some(path) => Machine::load(path, datapath).unwrap_or_else(|e| usage_error(&e)),
+      None => Machine::standard(datapath),
+    };
+    let mut cpu = Emulator::with_machine(positional[0], &machine);
+    cpu.set_max_cycles(max_cycles);
+    cpu.set_timeout(timeout);
+    if let Some(frame_cycles) = frame_cycles {
+      cpu.set_frame_cycles(frame_cycles);
+    }
+    cpu.set_clock_hz(clock_hz);
+    if let Some(dma_cycles) = dma_cycles {
+      cpu.set_dma_cycles(dma_cycles);
+    }
+    cpu.set_timing(timing);
+    cpu.set_pipeline(pipeline_report.map(|path| Pipeline::new(path, forwarding, branch_penalty)));
+    cpu.set_profiler(profile_report.map(Profiler::new));
+    cpu.set_coverage(coverage_report.map(Coverage::new));
+    cpu.set_export_views(views_dir.map(String::from));
+    cpu.set_stack_limit(stack_limit);
+    cpu.set_sanitizer(sanitizer);
+    if protect_code {
+      cpu.protect_code(writable_code);
+    }
+    cpu.set_detect_smc(detect_smc);
+    if console {
+      cpu.start_console();
+    }
+    for watchpoint in watchpoints {
+      cpu.add_watchpoint(watchpoint);
+    }
+    if symbols_path.is_some() || source_map_path.is_some() {
+      let mut symbols = SymbolTable::default();
+      if let Some(Err(e)) = symbols_path.map(|path| symbols.load_symbols(path)) {
+        usage_error(&e);
+      }
+      if let Some(Err(e)) = source_map_path.map(|path| symbols.load_source_map(path)) {
+        usage_error(&e);
+      }
+      cpu.set_symbols(symbols);
+    }
+    match cpu.run_until_stop(true) {
+      StopReason::Halted(result) => {
+        println!("<< {} >>", result); // print a newline
+        // process::exit(i32::from(result));
+        process::exit(0);
+      },
+      reason => {
+        eprintln!("{}", reason);
+        process::exit(1);
+      }
+    }
   } else {
-    println!("Usage: bemu file.bin");
+    println!("{}", USAGE);
     process::exit(64);
   }
-}
\ No newline at end of file
+}
+
+fn parse_option<T: std::str::FromStr>(option: &str, value: &str) -> T {
+  value.parse().unwrap_or_else(|_| usage_error(&format!("invalid value {value} for {option}")))
+}
+
+// a count that makes no sense as 0, like a clock rate
+fn parse_nonzero(option: &str, value: &str) -> u64 {
+  match parse_option(option, value) {
+    0 => usage_error(&format!("{option} must be more than 0")),
+    n => n,
+  }
+}
+
+fn parse_address(option: &str, value: &str) -> u16 {
+  config::parse_integer(value)
+    .and_then(|address| u16::try_from(address).ok())
+    .unwrap_or_else(|| usage_error(&format!("invalid address {value} for {option}")))
+}
+
+// a watchpoint like w:0xFFE0-0xFFEF
+fn parse_watchpoint(value: &str) -> Watchpoint {
+  let (kind, range) = value.split_once(':').unwrap_or_else(|| usage_error(&format!("invalid watchpoint {value}")));
+  let kind = match kind {
+    "r" | "read" => WatchKind::Read,
+    "w" | "write" => WatchKind::Write,
+    "c" | "change" => WatchKind::Change,
+    _ => usage_error(&format!("invalid watchpoint kind {kind}")),
+  };
+  let range = config::parse_range(range).unwrap_or_else(|| usage_error(&format!("invalid address range {range}")));
+  Watchpoint { kind, range }
+}
+
+fn parse_sanitizer(value: &str) -> Sanitizer {
+  match value {
+    "warn" => Sanitizer::Warn,
+    "trap" => Sanitizer::Trap,
+    _ => usage_error("--sanitize must be warn or trap"),
+  }
+}
+
+fn parse_switch(option: &str, value: &str) -> bool {
+  match value {
+    "on" => true,
+    "off" => false,
+    _ => usage_error(&format!("{option} must be on or off")),
+  }
+}
+
+fn usage_error(message: &str) -> ! {
+  eprintln!("{}", message);
+  println!("{}", USAGE);
+  process::exit(64);
+}
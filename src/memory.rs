@@ -3,44 +3,143 @@ use std::collections::VecDeque;
 use std::u16;
 use std::fs;
 use std::fs::File;
-use std::io::{self, Write};
+use std::io::Write;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
-pub const STACK_START : usize = 0xA000;
+use crate::bus::{Bus, Device};
+use crate::devices::{TileMapPort, FrameBufferPort, SpriteDataPort, SpriteRegisters, Register, Ps2Keyboard,
+    PaletteRam, PaletteModes, TileAttributesPort, SpriteTable, CollisionRegisters, BitmapWindow, TextRegisters,
+    ConsoleUart};
+use crate::machine::{Machine, DeviceKind};
+use crate::interrupts::{Interrupts, InterruptController, IRQ_DMA};
+use crate::dma::{Dma, DmaController};
+use crate::video::{VideoControl, VideoTiming, VideoMemory, Renderer, Frame};
+
+pub const STACK_START : usize = 0x9FFF; // the stack grows down from just below the sprite map
 
 pub const FRAME_WIDTH: u32 = 1024;
 pub const FRAME_HEIGHT: u32 = 512;
 pub const TILE_SIZE: u32 = 8;
 const TILES_NUM: u32 = 128;
 const TILE_DATA_SIZE: u32 = TILE_SIZE * TILE_SIZE;
-pub const SPRITE_SIZE: u32 = 32;
-const SPRITES_NUM: u32 = 8;
+pub const SPRITE_SIZE: u32 = 32; // the largest, and the one the sprite map is drawn in
+const SPRITES_NUM: u32 = 32;
+const LEGACY_SPRITES_NUM: u32 = 8; // the ones the sprite registers move, one per block of the sprite map
 const SPRITE_DATA_SIZE: u32 = SPRITE_SIZE * SPRITE_SIZE;
 
-const TILE_MAP_START : usize = 0xC000;
-const TILE_MAP_SIZE : usize = 0x2000;
-const FRAME_BUFFER_START : usize = 0xE000;
-const FRAME_BUFFER_SIZE : usize = 0x1000;
-const PS2_STREAM : usize = 0xFFFF;
-const UART_TX : usize = 0xF000;
-const V_SCROLL_START : usize = 0xFFFE;
-const H_SCROLL_START : usize = 0xFFFD;
-const SCALE_REGISTER_START : usize = 0xFFFC; // each pixel is repeated 2^n times
-const SPRITE_MAP_START : usize = 0xA000;
-const SPRITE_MAP_SIZE : usize = 0x2000;
-const SPRITE_REGISTERS_START : usize = 0xFFE0;  // every consecutive pair of words correspond to 
-const SPIRTE_REGISTERS_SIZE : usize = 0x10;     // the y and x coordinates, respectively of a sprite
+pub const TILE_MAP_START : usize = 0xC000;
+pub const TILE_MAP_SIZE : usize = 0x2000;
+pub const FRAME_BUFFER_START : usize = 0xE000;
+pub const FRAME_BUFFER_SIZE : usize = 0x1000;
+pub const PS2_STREAM : usize = 0xFFFF;
+pub const UART_TX : usize = 0xF000;
+pub const INTERRUPTS_START : usize = 0xF010; // enable, pending and vector
+pub const INTERRUPTS_SIZE : usize = 3;
+pub const VIDEO_TIMING_START : usize = 0xF020; // vblank status, frame counter, scanline and line compare
+pub const VIDEO_TIMING_SIZE : usize = 4;
+pub const PALETTE_MODES_START : usize = 0xF030; // a bit per tile, then a bit per sprite
+pub const PALETTE_MODES_SIZE : usize = 10;
+pub const PALETTE_START : usize = 0xF100;
+pub const PALETTE_SIZE : usize = 0x100;
+pub const TILE_ATTRIBUTES_START : usize = 0xF400; // a nibble per frame buffer tile, four per word
+pub const TILE_ATTRIBUTES_SIZE : usize = 0x800;
+// bits of a tile's attributes
+pub const TILE_HFLIP : u8 = 1 << 0;
+pub const TILE_VFLIP : u8 = 1 << 1;
+pub const TILE_PRIORITY : u8 = 1 << 2; // drawn above the sprites
+pub const TILE_PALETTE : u8 = 1 << 3;  // indexed pixels use the upper half of the palette
+pub const SPRITE_TABLE_START : usize = 0xF200; // x, y, control and pointer of every sprite
+pub const SPRITE_TABLE_SIZE : usize = 0x80;
+// bits of a sprite's control word
+pub const SPRITE_ENABLE : u16 = 1 << 0;
+pub const SPRITE_HFLIP : u16 = 1 << 1;
+pub const SPRITE_VFLIP : u16 = 1 << 2;
+pub const SPRITE_BEHIND : u16 = 1 << 3; // only shows where the background is 0
+pub const SPRITE_SIZE_SHIFT : u16 = 4;  // 2 bits: 8, 16 or 32 pixels square
+pub const COLLISIONS_START : usize = 0xF280; // which sprites touched a sprite, the background and each other
+pub const COLLISIONS_SIZE : usize = 4 + 2 * SPRITES_NUM as usize;
+pub const VIDEO_MODE_START : usize = 0xF040;
+pub const BITMAP_BANK_START : usize = 0xF041;     // which part of the bitmap the window shows
+pub const BITMAP_WINDOW_START : usize = 0xFC00;
+pub const BITMAP_WINDOW_SIZE : usize = 0x200;
+// values of the video mode register
+pub const VIDEO_MODE_TILES : u16 = 0;
+pub const VIDEO_MODE_BITMAP : u16 = 1;
+pub const VIDEO_MODE_TEXT : u16 = 2;
+pub const BITMAP_WIDTH: u32 = 160;
+pub const BITMAP_HEIGHT: u32 = 120;
+pub const TEXT_COLUMNS: u32 = 80; // text mode shows the bitmap's words as a grid of characters
+pub const TEXT_ROWS: u32 = 60;
+pub const TEXT_START : usize = 0xF050; // cursor column and row, control, attribute and scroll
+pub const TEXT_SIZE : usize = 5;
+pub const DMA_START : usize = 0xF060; // source, destination, length, rows, strides, fill value and control
+pub const DMA_SIZE : usize = 8;
+// bits of the text control register
+pub const TEXT_CURSOR : u16 = 1 << 0; // show the cursor
+pub const TEXT_ECHO : u16 = 1 << 1;   // print what is written to the uart at the cursor
+const TEXT_ATTRIBUTE : u16 = 0x07;    // light gray on black
+pub const V_SCROLL_START : usize = 0xFFFE;
+pub const H_SCROLL_START : usize = 0xFFFD;
+pub const SCALE_REGISTER_START : usize = 0xFFFC; // each pixel is repeated 2^n times
+pub const SPRITE_MAP_START : usize = 0xA000;
+pub const SPRITE_MAP_SIZE : usize = 0x2000;
+pub const SPRITE_REGISTERS_START : usize = 0xFFE0;  // every consecutive pair of words correspond to 
+pub const SPIRTE_REGISTERS_SIZE : usize = 0x10;     // the y and x coordinates, respectively of a sprite
 
 pub struct Memory {
   ram: Vec<u16>,   
+  program_size: usize, // words of ram taken by the loaded binary
   frame_buffer: Arc<RwLock<FrameBuffer>>,
   tile_map: Arc<RwLock<TileMap>>, 
   io_buffer: Arc<RwLock<VecDeque<u16>>>,
   vscroll_register: Arc<RwLock<u16>>,
   hscroll_register: Arc<RwLock<u16>>,
   scale_register: Arc<RwLock<u16>>,
+  video_mode_register: Arc<RwLock<u16>>,
+  bitmap: Arc<RwLock<Bitmap>>,
+  console: Arc<RwLock<Console>>,
+  dma: Arc<RwLock<Dma>>,
   sprite_map: Arc<RwLock<SpriteMap>>,
+  palette: Arc<RwLock<Palette>>,
+  video: VideoMemory, // everything the renderer reads, for the debug viewers
+  interrupts: Arc<Interrupts>,
+  video_control: Arc<VideoControl>,
+  frame: Arc<RwLock<Frame>>,
+  bus: Bus,
+  watchpoints: Vec<Watchpoint>,
+  watch_hit: Option<WatchHit>,
+  initialized: Vec<bool>, // per word of ram, whether anything has written it
+  sanitize: bool,
+  uninitialized_read: Option<u16>,
+  // when set, stores into the program are faults except in these ranges
+  code_protection: Option<Vec<RangeInclusive<u16>>>,
+  stored: Vec<bool>, // per word, whether a store has written it since load
+  detect_smc: bool,
+  modified_fetch: Option<u16>,
+  dma_fault: Option<u16>, // a protected address a transfer stopped at
+  last_read: Option<u16>, // what the instruction being executed loaded from
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Change, // a write of a different value
+}
+
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub range: RangeInclusive<u16>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WatchHit {
+    pub kind: WatchKind,
+    pub address: u16,
+    pub old: u16, // the value before the access
+    pub new: u16, // the value read or written
 }
 
 // an 80x60 framebuffer of 8-bit tile values
@@ -48,6 +147,22 @@ pub struct FrameBuffer {
     pub width: u32, // number of tiles in the x direction
     pub height: u32, // number of tiles in the y direction
     tile_ptrs: Vec<u16>,
+    attributes: Vec<u16>, // a nibble per tile
+}
+
+// a 160x120 bitmap of 4-bit palette indexes, four pixels per word with the leftmost in the low nibble
+pub struct Bitmap {
+    pixels: Vec<u16>,
+}
+
+// The state of text mode. Each word of the grid is a character in the low
+// byte and its attribute in the high byte, the foreground colour in the low
+// nibble and the background in the high one.
+pub struct Console {
+    pub cursor_x: u16,
+    pub cursor_y: u16,
+    pub control: u16,
+    pub attribute: u16, // of the characters the uart echoes and of the lines scrolling clears
 }
 
 pub struct TileMap {
@@ -59,24 +174,56 @@ pub struct Tile {
     pub pixels: Vec<u16>, // an 8x8 tile of pixels
 }
 
+// The sprite memory and the sprites drawn from it. A sprite's pointer is
+// the word of sprite memory its pixels start at, row by row, so several
+// sprites can show the same pixels.
 pub struct SpriteMap {
+    pub pixels: Vec<u16>,
     pub sprites: Vec<Sprite>,
 }
 
+// 256 colours that tiles and sprites in indexed mode pick from with the
+// low byte of their pixels, and which tiles and sprites are in that mode
+pub struct Palette {
+    pub colors: Vec<u16>,
+    pub indexed_tiles: Vec<bool>,
+    pub indexed_sprites: Vec<bool>,
+}
+
+// The sprites the video found touching since the program last read the
+// registers, a bit per sprite. Only opaque pixels on the screen count,
+// and the background counts where its pixels aren't 0.
+pub struct Collisions {
+    pub sprites: u32,    // touched another sprite
+    pub background: u32, // touched the background
+    pub pairs: Vec<u32>, // the sprites each sprite touched
+}
+
 #[derive(Clone)]
 pub struct Sprite {
     pub x: u16,
     pub y: u16,
-    pub pixels: Vec<u16>, // a 32x32 tile of pixels
+    pub control: u16,
+    pub pointer: u16,
 }
 
 impl Memory {
     pub fn new(ram_init: Vec<u16>, datapath: &str) -> Memory {
+        Memory::with_machine(ram_init, &Machine::standard(datapath))
+    }
+
+    pub fn with_machine(ram_init: Vec<u16>, machine: &Machine) -> Memory {
         // Fill ram to size of address space
+        let program_size = ram_init.len();
+        if let Err(e) = machine.validate(program_size) {
+            panic!("invalid memory layout:\n{}", e);
+        }
         let mut ram = ram_init;
         ram.resize(1 << 16, 0);
+        let mut initialized = vec![false; 1 << 16];
+        initialized[..program_size].fill(true);
 
-        let binding = fs::read_to_string(&format!("{datapath}/mem.hex")).unwrap();
+        let binding = fs::read_to_string(&machine.mem_hex).unwrap();
         let mem_text = binding.lines();
         let mut index: u16 = 0;
         for line in mem_text {
@@ -91,20 +238,119 @@ impl Memory {
                 let value = u16::from_str_radix(modified_line, 16);
                 if !value.is_err() {
                     ram[index as usize] = value.unwrap();
+                    initialized[index as usize] = true;
                     index = index + 1;
                 }
             }
         }
 
+        let frame_buffer = Arc::new(RwLock::new(FrameBuffer::new(FRAME_WIDTH, FRAME_HEIGHT)));
+        let io_buffer = Arc::new(RwLock::new(VecDeque::new()));
+        let vscroll_register = Arc::new(RwLock::new(0));
+        let hscroll_register = Arc::new(RwLock::new(0));
+        let scale_register = Arc::new(RwLock::new(0));
+        let video_mode_register = Arc::new(RwLock::new(VIDEO_MODE_TILES));
+        let bitmap_bank_register = Arc::new(RwLock::new(0));
+        let bitmap = Arc::new(RwLock::new(Bitmap::new()));
+        let console = Arc::new(RwLock::new(Console::new()));
+        let dma = Arc::new(RwLock::new(Dma::new()));
+        let mut tiles = TileMap::load(&machine.tile_map);
+        let mut sprites = SpriteMap::load(&machine.sprite_map);
+        let mut palette = Palette::from_maps(&tiles, &sprites);
+        if machine.indexed_tiles {
+            tiles.quantize(&palette);
+            palette.indexed_tiles.fill(true);
+        }
+        if machine.indexed_sprites {
+            sprites.quantize(&palette);
+            palette.indexed_sprites.fill(true);
+        }
+        let tile_map = Arc::new(RwLock::new(tiles));
+        let sprite_map = Arc::new(RwLock::new(sprites));
+        let palette = Arc::new(RwLock::new(palette));
+        let collisions = Arc::new(RwLock::new(Collisions::new()));
+        let interrupts = Arc::new(Interrupts::default());
+        let video_control = Arc::new(VideoControl::default());
+        let frame = Arc::new(RwLock::new(Frame::new()));
+        let video = VideoMemory {
+            frame_buffer: Arc::clone(&frame_buffer),
+            tile_map: Arc::clone(&tile_map),
+            vscroll_register: Arc::clone(&vscroll_register),
+            hscroll_register: Arc::clone(&hscroll_register),
+            scale_register: Arc::clone(&scale_register),
+            sprite_map: Arc::clone(&sprite_map),
+            palette: Arc::clone(&palette),
+            collisions: Arc::clone(&collisions),
+            video_mode_register: Arc::clone(&video_mode_register),
+            bitmap: Arc::clone(&bitmap),
+            console: Arc::clone(&console),
+        };
+
+        let devices: Vec<(RangeInclusive<u16>, Box<dyn Device>)> = machine.devices.iter().map(|(kind, range)| {
+            let device: Box<dyn Device> = match kind {
+                DeviceKind::TileMap => Box::new(TileMapPort::new(Arc::clone(&tile_map))),
+                DeviceKind::FrameBuffer => Box::new(FrameBufferPort::new(Arc::clone(&frame_buffer))),
+                DeviceKind::SpriteMap => Box::new(SpriteDataPort::new(Arc::clone(&sprite_map))),
+                DeviceKind::SpriteRegisters => Box::new(SpriteRegisters::new(Arc::clone(&sprite_map))),
+                DeviceKind::VScroll => Box::new(Register::new("vertical scroll", Arc::clone(&vscroll_register))),
+                DeviceKind::HScroll => Box::new(Register::new("horizontal scroll", Arc::clone(&hscroll_register))),
+                DeviceKind::Scale => Box::new(Register::new("scale", Arc::clone(&scale_register))),
+                DeviceKind::Ps2 => Box::new(Ps2Keyboard::new(Arc::clone(&io_buffer))),
+                DeviceKind::Uart => Box::new(ConsoleUart::new(Arc::clone(&console), Arc::clone(&bitmap))),
+                DeviceKind::Interrupts => Box::new(InterruptController::new(Arc::clone(&interrupts))),
+                DeviceKind::VideoTiming => {
+                    let renderer = Renderer::new(video.clone(), Arc::clone(&frame));
+                    Box::new(VideoTiming::new(Arc::clone(&video_control), Arc::clone(&interrupts), renderer))
+                },
+                DeviceKind::Palette => Box::new(PaletteRam::new(Arc::clone(&palette))),
+                DeviceKind::TileAttributes => Box::new(TileAttributesPort::new(Arc::clone(&frame_buffer))),
+                DeviceKind::SpriteTable => Box::new(SpriteTable::new(Arc::clone(&sprite_map))),
+                DeviceKind::Collisions => Box::new(CollisionRegisters::new(Arc::clone(&collisions))),
+                DeviceKind::PaletteModes => Box::new(PaletteModes::new(Arc::clone(&palette))),
+                DeviceKind::VideoMode => Box::new(Register::new("video mode", Arc::clone(&video_mode_register))),
+                DeviceKind::BitmapBank => Box::new(Register::new("bitmap bank", Arc::clone(&bitmap_bank_register))),
+                DeviceKind::Bitmap => Box::new(BitmapWindow::new(Arc::clone(&bitmap), Arc::clone(&bitmap_bank_register))),
+                DeviceKind::Text => Box::new(TextRegisters::new(Arc::clone(&console), Arc::clone(&bitmap))),
+                DeviceKind::Dma => Box::new(DmaController::new(Arc::clone(&dma))),
+            };
+            (range.clone(), device)
+        }).collect();
+        let mut bus = Bus::new(devices).unwrap_or_else(|e| panic!("invalid machine: {}", e));
+        for (kind, range) in &machine.devices {
+            bus.set_write_through(*range.start(), machine.write_through.contains(kind));
+        }
+
         Memory {
             ram,
-            frame_buffer: Arc::new(RwLock::new(FrameBuffer::new(FRAME_WIDTH, FRAME_HEIGHT))),
-            tile_map: Arc::new(RwLock::new(TileMap::load(&format!("{datapath}/tilemap.bmp")))),
-            io_buffer: Arc::new(RwLock::new(VecDeque::new())),
-            vscroll_register: Arc::new(RwLock::new(0)),
-            hscroll_register: Arc::new(RwLock::new(0)),
-            scale_register: Arc::new(RwLock::new(0)),
-            sprite_map: Arc::new(RwLock::new(SpriteMap::load(&format!("{datapath}/spritemap.bmp"))))
+            program_size,
+            frame_buffer,
+            tile_map,
+            io_buffer,
+            vscroll_register,
+            hscroll_register,
+            scale_register,
+            video_mode_register,
+            bitmap,
+            console,
+            dma,
+            sprite_map,
+            palette,
+            video,
+            interrupts,
+            video_control,
+            frame,
+            bus,
+            watchpoints: vec![],
+            watch_hit: None,
+            initialized,
+            sanitize: false,
+            uninitialized_read: None,
+            code_protection: None,
+            stored: vec![false; 1 << 16],
+            detect_smc: false,
+            modified_fetch: None,
+            dma_fault: None,
+            last_read: None,
         }
     }
 
@@ -115,71 +361,160 @@ impl Memory {
     pub fn get_hscroll_register(&self) -> Arc<RwLock<u16>> { return Arc::clone(&self.hscroll_register) }
     pub fn get_scale_register(&self) -> Arc<RwLock<u16>> { return Arc::clone(&self.scale_register) }
     pub fn get_sprite_map(&self) -> Arc<RwLock<SpriteMap>> { return Arc::clone(&self.sprite_map) }
+    pub fn get_palette(&self) -> Arc<RwLock<Palette>> { Arc::clone(&self.palette) }
+    pub fn get_video_mode_register(&self) -> Arc<RwLock<u16>> { Arc::clone(&self.video_mode_register) }
+    pub fn get_bitmap(&self) -> Arc<RwLock<Bitmap>> { Arc::clone(&self.bitmap) }
+    pub fn get_video_memory(&self) -> VideoMemory { self.video.clone() }
+
+    // text mode with the uart echoed on the screen, for console programs
+    pub fn start_console(&mut self) {
+        *self.video_mode_register.write().unwrap() = VIDEO_MODE_TEXT;
+        self.console.write().unwrap().control |= TEXT_CURSOR | TEXT_ECHO;
+    }
+    pub fn get_interrupts(&self) -> Arc<Interrupts> { Arc::clone(&self.interrupts) }
+    pub fn get_video_control(&self) -> Arc<VideoControl> { Arc::clone(&self.video_control) }
+    pub fn get_frame(&self) -> Arc<RwLock<Frame>> { Arc::clone(&self.frame) }
 
-    pub fn read(&mut self, addr: usize) -> u16 {
-        if addr >= TILE_MAP_START && addr < TILE_MAP_START + TILE_MAP_SIZE {
-            return self.tile_map.read().unwrap().get_tile_word((addr - TILE_MAP_START) as u32);
-        }
-        if addr >= FRAME_BUFFER_START && addr < FRAME_BUFFER_START + FRAME_BUFFER_SIZE {
-            return self.frame_buffer.read().unwrap().get_tile_pair((addr - FRAME_BUFFER_START) as u32);
-        }
-        if addr == PS2_STREAM {
-            return self.io_buffer.write().unwrap().pop_front().unwrap_or(0);
-        }
-        if addr >= SPRITE_MAP_START && addr < SPRITE_MAP_START + SPRITE_MAP_SIZE {
-            return self.sprite_map.read().unwrap().get_sprite_word((addr - SPRITE_MAP_START) as u32);
+    // adds a custom peripheral, unless it overlaps one that is already mapped
+    pub fn map_device(&mut self, range: RangeInclusive<u16>, device: Box<dyn Device>) -> Result<(), String> {
+        self.bus.map(range, device)
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        self.bus.tick(cycles);
+        let completed = self.dma.write().unwrap().tick(cycles);
+        if let Some(transfer) = completed {
+            // a write into the protected program stops the transfer before it happens, as it does a store
+            for (destination, data) in transfer.words() {
+                if self.is_write_protected(usize::from(destination)) {
+                    self.dma_fault = Some(destination);
+                    return;
+                }
+                let data = data.unwrap_or_else(|source| self.read_mapped(usize::from(source)));
+                self.write(usize::from(destination), data);
+            }
+            self.interrupts.raise(IRQ_DMA);
         }
-        if addr >= SPRITE_REGISTERS_START && addr < SPRITE_REGISTERS_START + SPIRTE_REGISTERS_SIZE {
-            return self.sprite_map.read().unwrap().get_sprite_reg((addr - SPRITE_REGISTERS_START) as u32);
+    }
+
+    // the protected address the last transfer stopped at, if any
+    pub fn take_dma_fault(&mut self) -> Option<u16> {
+        self.dma_fault.take()
+    }
+
+    pub fn set_dma_cycles(&mut self, cycles_per_word: u64) {
+        self.dma.write().unwrap().set_cycles_per_word(cycles_per_word);
+    }
+
+    pub fn program_size(&self) -> usize {
+        self.program_size
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    // the first watchpoint hit since the last call, if any
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    fn check_watchpoints(&mut self, access: WatchKind, addr: usize, old: u16, new: u16) {
+        if self.watch_hit.is_some() {
+            return;
         }
-        if addr == V_SCROLL_START {
-            return *self.vscroll_register.read().unwrap();
+        let address = addr as u16;
+        let hit = self.watchpoints.iter().find(|watchpoint| {
+            watchpoint.range.contains(&address) && match watchpoint.kind {
+                WatchKind::Change => access == WatchKind::Write && old != new,
+                kind => kind == access,
+            }
+        });
+        if let Some(watchpoint) = hit {
+            self.watch_hit = Some(WatchHit { kind: watchpoint.kind, address, old, new });
         }
-        if addr == H_SCROLL_START {
-            return *self.hscroll_register.read().unwrap();
+    }
+
+    // remember reads of ram that nothing has written yet
+    pub fn set_sanitize(&mut self, sanitize: bool) {
+        self.sanitize = sanitize;
+    }
+
+    // the first read of uninitialized ram since the last call, if any
+    pub fn take_uninitialized_read(&mut self) -> Option<u16> {
+        self.uninitialized_read.take()
+    }
+
+    // make the loaded program read-only, apart from the ranges it is allowed to modify
+    pub fn protect_code(&mut self, writable: Vec<RangeInclusive<u16>>) {
+        self.code_protection = Some(writable);
+    }
+
+    // whether a store to an address should fault instead of happening
+    pub fn is_write_protected(&self, addr: usize) -> bool {
+        match &self.code_protection {
+            Some(writable) => addr < self.program_size && !writable.iter().any(|range| range.contains(&(addr as u16))),
+            None => false,
         }
-        if addr == SCALE_REGISTER_START {
-            return *self.scale_register.read().unwrap();
+    }
+
+    // remember fetches of instructions that a store has modified since load
+    pub fn set_detect_smc(&mut self, detect_smc: bool) {
+        self.detect_smc = detect_smc;
+    }
+
+    // the first fetch of a modified instruction since the last call, if any
+    pub fn take_modified_fetch(&mut self) -> Option<u16> {
+        self.modified_fetch.take()
+    }
+
+    // whether the instruction just executed polled a device that has been empty for a long time
+    pub fn is_polling_idle(&self) -> bool {
+        self.last_read.is_some_and(|addr| self.bus.is_waiting(addr))
+    }
+
+    pub fn read(&mut self, addr: usize) -> u16 {
+        self.last_read = Some(addr as u16);
+        let data = self.read_mapped(addr);
+        self.check_watchpoints(WatchKind::Read, addr, data, data);
+        if self.sanitize && !self.initialized[addr] && !self.bus.contains(addr as u16) && self.uninitialized_read.is_none() {
+            self.uninitialized_read = Some(addr as u16);
         }
-        if addr == UART_TX {
-            panic!("attempting to read output port (address {})", UART_TX);
+        data
+    }
+
+    // reads an instruction, which doesn't trigger read watchpoints
+    pub fn fetch(&mut self, addr: usize) -> u16 {
+        self.last_read = None;
+        if self.detect_smc && self.stored[addr] && self.modified_fetch.is_none() {
+            self.modified_fetch = Some(addr as u16);
         }
-        return self.ram[addr];
+        self.read_mapped(addr)
+    }
+
+    fn read_mapped(&mut self, addr: usize) -> u16 {
+        self.bus.read(addr as u16).unwrap_or(self.ram[addr])
+    }
+
+    // reads what's at an address without any side effects on the devices
+    pub fn peek(&self, addr: usize) -> u16 {
+        self.bus.peek(addr as u16).unwrap_or(self.ram[addr])
     }
 
     pub fn write(&mut self, addr: usize, data: u16) {
-        if addr >= TILE_MAP_START && addr < TILE_MAP_START + TILE_MAP_SIZE {
-            self.tile_map.write().unwrap().set_tile_word((addr - TILE_MAP_START) as u32, data);
-        }
-        if addr >= FRAME_BUFFER_START && addr < FRAME_BUFFER_START + FRAME_BUFFER_SIZE {
-            self.frame_buffer.write().unwrap().set_tile_pair((addr - FRAME_BUFFER_START) as u32, data);
-        }
-        if addr == PS2_STREAM {
-            panic!("attempting to write to read input port (address {})", PS2_STREAM);
-        }
-        if addr == UART_TX {
-            print!("{}", (data as u8) as char);
-            io::stdout().flush().unwrap();
-        }
-        if addr == V_SCROLL_START {
-            *self.vscroll_register.write().unwrap() = data;
-        }
-        if addr == H_SCROLL_START {
-            *self.hscroll_register.write().unwrap() = data;
-        }
-        if addr == SCALE_REGISTER_START {
-            *self.scale_register.write().unwrap() = data;
-        }
-        if addr >= SPRITE_MAP_START && addr < SPRITE_MAP_START + SPRITE_MAP_SIZE {
-            self.sprite_map.write().unwrap().set_sprite_word((addr - SPRITE_MAP_START) as u32, data);
-        }
-        if addr >= SPRITE_REGISTERS_START && addr < SPRITE_REGISTERS_START + SPIRTE_REGISTERS_SIZE {
-            self.sprite_map.write().unwrap().set_sprite_reg((addr - SPRITE_REGISTERS_START) as u32, data);
+        self.bus.note_store();
+        if !self.watchpoints.is_empty() {
+            let old = self.peek(addr);
+            self.check_watchpoints(WatchKind::Write, addr, old, data);
         }
         if addr == 0 {
             println!("Writing to address 0x0000: 0x{:04X}", data);
         }
-        self.ram[addr] = data;
+        if !self.bus.write(addr as u16, data) || self.bus.writes_through(addr as u16) {
+            self.ram[addr] = data;
+        }
+        self.initialized[addr] = true;
+        self.stored[addr] = true;
     }
 }
 
@@ -192,6 +527,7 @@ impl FrameBuffer {
             width,
             height,
             tile_ptrs: vec![0; (width * height / 2) as usize],
+            attributes: vec![0; (width * height / 4) as usize],
         }
     }
 
@@ -213,6 +549,32 @@ impl FrameBuffer {
         }
     }
 
+    pub fn set_attribute_word(&mut self, i: u32, attributes: u16) {
+        // we're packing the attributes of 4 tiles into 1 word
+        if i < self.attributes.len() as u32 {
+            self.attributes[i as usize] = attributes;
+        } else {
+            panic!("Tile attributes out of bounds: {}", i);
+        }
+    }
+
+    pub fn get_attribute_word(&self, i: u32) -> u16 {
+        if i < self.attributes.len() as u32 {
+            self.attributes[i as usize]
+        } else {
+            panic!("Tile attributes out of bounds");
+        }
+    }
+
+    pub fn get_attributes(&self, x: u32, y: u32) -> u8 {
+        if x < self.width && y < self.height {
+            let idx: usize = (x + y * self.width) as usize;
+            ((self.attributes[idx / 4] >> (idx % 4 * 4)) & 0xf) as u8
+        } else {
+            panic!("Tile coordinates out of bounds");
+        }
+    }
+
     pub fn get_tile(&self, x: u32, y: u32) -> u8 {
         if x < self.width && y < self.height {
             let idx: usize = (x + y * self.width) as usize;
@@ -227,6 +589,108 @@ impl FrameBuffer {
     }
 }
 
+impl Bitmap {
+    pub fn new() -> Bitmap {
+        Bitmap { pixels: vec![0; (BITMAP_WIDTH * BITMAP_HEIGHT / 4) as usize] }
+    }
+
+    // words past the end of the bitmap read as 0 and ignore writes
+    pub fn get_word(&self, i: u32) -> u16 {
+        self.pixels.get(i as usize).copied().unwrap_or(0)
+    }
+
+    pub fn set_word(&mut self, i: u32, data: u16) {
+        if let Some(word) = self.pixels.get_mut(i as usize) {
+            *word = data;
+        }
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> u8 {
+        let idx = (x + y * BITMAP_WIDTH) as usize;
+        ((self.pixels[idx / 4] >> (idx % 4 * 4)) & 0xf) as u8
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, value: u8) {
+        let idx = (x + y * BITMAP_WIDTH) as usize;
+        let shift = idx % 4 * 4;
+        self.pixels[idx / 4] = (self.pixels[idx / 4] & !(0xf << shift)) | (u16::from(value & 0xf) << shift);
+    }
+}
+
+impl Bitmap {
+    pub fn get_cell(&self, column: u32, row: u32) -> u16 {
+        self.pixels[(column + row * TEXT_COLUMNS) as usize]
+    }
+
+    pub fn set_cell(&mut self, column: u32, row: u32, cell: u16) {
+        self.pixels[(column + row * TEXT_COLUMNS) as usize] = cell;
+    }
+}
+
+impl Default for Bitmap {
+    fn default() -> Bitmap {
+        Bitmap::new()
+    }
+}
+
+impl Console {
+    pub fn new() -> Console {
+        Console { cursor_x: 0, cursor_y: 0, control: 0, attribute: TEXT_ATTRIBUTE }
+    }
+
+    // writes a character at the cursor and moves it on like a terminal would
+    pub fn put_char(&mut self, grid: &mut Bitmap, c: u8) {
+        match c {
+            b'\n' => {
+                self.cursor_x = 0;
+                self.cursor_y = self.cursor_y.saturating_add(1);
+            },
+            b'\r' => self.cursor_x = 0,
+            0x08 => self.cursor_x = self.cursor_x.saturating_sub(1),
+            _ => {
+                if u32::from(self.cursor_x) >= TEXT_COLUMNS {
+                    self.cursor_x = 0;
+                    self.cursor_y = self.cursor_y.saturating_add(1);
+                }
+                self.keep_cursor_on_screen(grid);
+                grid.set_cell(u32::from(self.cursor_x), u32::from(self.cursor_y), u16::from(c) | (self.attribute << 8));
+                self.cursor_x += 1;
+            },
+        }
+        self.keep_cursor_on_screen(grid);
+    }
+
+    // a cursor below the last row scrolls the text up until it is on it
+    fn keep_cursor_on_screen(&mut self, grid: &mut Bitmap) {
+        let last_row = TEXT_ROWS as u16 - 1;
+        if self.cursor_y > last_row {
+            self.scroll(grid, self.cursor_y - last_row);
+            self.cursor_y = last_row;
+        }
+    }
+
+    // moves the text up this many lines, clearing the ones at the bottom
+    pub fn scroll(&self, grid: &mut Bitmap, lines: u16) {
+        let lines = u32::from(lines).min(TEXT_ROWS);
+        for row in 0..TEXT_ROWS {
+            for column in 0..TEXT_COLUMNS {
+                let cell = if row + lines < TEXT_ROWS {
+                    grid.get_cell(column, row + lines)
+                } else {
+                    u16::from(b' ') | (self.attribute << 8)
+                };
+                grid.set_cell(column, row, cell);
+            }
+        }
+    }
+}
+
+impl Default for Console {
+    fn default() -> Console {
+        Console::new()
+    }
+}
+
 impl Tile {
     pub fn black() -> Tile {
         Tile {
@@ -308,6 +772,15 @@ impl TileMap {
         Ok(())
     }
 
+    // turns every pixel into the index of its colour in the palette
+    pub fn quantize(&mut self, palette: &Palette) {
+        for tile in &mut self.tiles {
+            for pixel in &mut tile.pixels {
+                *pixel = palette.quantize(*pixel);
+            }
+        }
+    }
+
     pub fn get_tile_word(&self, addr: u32) -> u16 {
         return self.tiles[(addr / TILE_DATA_SIZE) as usize].pixels[(addr % TILE_DATA_SIZE) as usize];
     }
@@ -317,34 +790,119 @@ impl TileMap {
     }
 }
 
+impl Palette {
+    // the distinct colours of the tile and sprite maps, as many as fit
+    pub fn from_maps(tile_map: &TileMap, sprite_map: &SpriteMap) -> Palette {
+        let mut colors: Vec<u16> = vec![];
+        let pixels = tile_map.tiles.iter().flat_map(|tile| &tile.pixels)
+            .chain(&sprite_map.pixels);
+        for &pixel in pixels {
+            if colors.len() == PALETTE_SIZE {
+                break;
+            }
+            if !colors.contains(&pixel) {
+                colors.push(pixel);
+            }
+        }
+        colors.resize(PALETTE_SIZE, 0);
+        Palette {
+            colors,
+            indexed_tiles: vec![false; TILES_NUM as usize],
+            indexed_sprites: vec![false; SPRITES_NUM as usize],
+        }
+    }
+
+    // the index of the closest colour, transparent pixels only match transparent entries
+    pub fn quantize(&self, color: u16) -> u16 {
+        let transparent = |color: u16| color & 0xf000 == 0xf000;
+        let distance = |other: u16| -> i32 {
+            (0..3).map(|channel| {
+                let difference = ((color >> (channel * 4)) & 0xf) as i32 - ((other >> (channel * 4)) & 0xf) as i32;
+                difference * difference
+            }).sum()
+        };
+        let closest = (0..self.colors.len())
+            .filter(|&i| transparent(self.colors[i]) == transparent(color))
+            .min_by_key(|&i| distance(self.colors[i]))
+            .unwrap_or(0);
+        closest as u16
+    }
+
+    // the colour a pixel shows as
+    pub fn resolve(&self, pixel: u16, indexed: bool) -> u16 {
+        if indexed {
+            self.colors[(pixel & 0xff) as usize]
+        } else {
+            pixel
+        }
+    }
+}
+
+impl Collisions {
+    pub fn new() -> Collisions {
+        Collisions { sprites: 0, background: 0, pairs: vec![0; SPRITES_NUM as usize] }
+    }
+}
+
+impl Default for Collisions {
+    fn default() -> Collisions {
+        Collisions::new()
+    }
+}
+
 impl Sprite {
     pub fn invisible() -> Sprite {
         Sprite {
             x: 0xFFFF,
             y: 0xFFFF,
-            pixels: vec![0xFFFF; SPRITE_DATA_SIZE as usize],
+            control: 0,
+            pointer: 0,
+        }
+    }
+
+    // the sprites the sprite registers move start out enabled, each showing its own block of the sprite map
+    fn standard(i: u32) -> Sprite {
+        if i < LEGACY_SPRITES_NUM {
+            Sprite {
+                control: SPRITE_ENABLE | (2 << SPRITE_SIZE_SHIFT),
+                pointer: (i * SPRITE_DATA_SIZE) as u16,
+                ..Sprite::invisible()
+            }
+        } else {
+            Sprite::invisible()
         }
     }
+
+    // the width and height in pixels
+    pub fn size(&self) -> u32 {
+        8 << ((self.control >> SPRITE_SIZE_SHIFT) & 3).min(2)
+    }
+}
+
+impl Default for SpriteMap {
+    fn default() -> SpriteMap {
+        SpriteMap::new()
+    }
 }
 
 impl SpriteMap {
-    pub fn new(size: usize) -> SpriteMap {
-        let sprites = vec![Sprite::invisible(); size];
-        SpriteMap { 
-            sprites
+    // transparent sprite memory
+    pub fn new() -> SpriteMap {
+        SpriteMap {
+            pixels: vec![0xFFFF; SPRITE_MAP_SIZE],
+            sprites: (0..SPRITES_NUM).map(Sprite::standard).collect(),
         }
     }
 
     pub fn load(filename: &str) -> SpriteMap {
         let img = bmp::open(filename).expect(&format!("Failed to open spritemap {}", filename));
-        if (img.get_width() * img.get_height()) / (SPRITE_SIZE * SPRITE_SIZE) < SPRITES_NUM {
+        if (img.get_width() * img.get_height()) / (SPRITE_SIZE * SPRITE_SIZE) < LEGACY_SPRITES_NUM {
             panic!("Loaded spritemap size mismatch");
         }
 
-        let mut sprites: Vec<Sprite> = vec![];
+        let mut pixels: Vec<u16> = vec![];
         for y in 0..(img.get_height() / SPRITE_SIZE) {
             for x in 0..(img.get_width() / SPRITE_SIZE) {
-                let mut pixels: Vec<u16> = vec![];
                 for py in 0..SPRITE_SIZE {
                     for px in 0..SPRITE_SIZE {
                         let p = img.get_pixel(x * SPRITE_SIZE + px, y * SPRITE_SIZE + py);
@@ -361,12 +919,11 @@ impl SpriteMap {
                         pixels.push(color);
                     }
                 }
-                sprites.push(Sprite{x: 0xFFFF, y: 0xFFFF, pixels});
             }
         }
         // only store the first 8 sprites
-        sprites.truncate(SPRITES_NUM as usize);
-        let map = SpriteMap{sprites};
+        pixels.resize(SPRITE_MAP_SIZE, 0xFFFF);
+        let map = SpriteMap { pixels, ..SpriteMap::new() };
         let mut path = PathBuf::from(filename);
         path.set_extension("hex");
         map.save_hex_map(path.to_str().unwrap()).expect("Failed to save hex sprite map");
@@ -376,14 +933,9 @@ impl SpriteMap {
     pub fn save_bin_map(&self, filename: &str) {
         let mut file = File::create(filename).expect("Failed to open sprite output");
         let mut data: Vec<u8> = vec![];
-        for sprite in &self.sprites {
-            for py in 0..SPRITE_SIZE {
-                for px in 0..SPRITE_SIZE {
-                    let p = sprite.pixels[(py * SPRITE_SIZE + px) as usize];
-                    data.push((p & 0x00ff) as u8);
-                    data.push(((p & 0xff00) >> 8) as u8);
-                }
-            }
+        for &p in &self.pixels {
+            data.push((p & 0x00ff) as u8);
+            data.push(((p & 0xff00) >> 8) as u8);
         }
         file.write_all(data.as_slice()).expect("Failed to write to bin");
     }
@@ -391,25 +943,56 @@ impl SpriteMap {
     pub fn save_hex_map(&self, filename: &str) -> Result<(), std::io::Error> {
         let mut file = File::create(filename)?;
         file.write(b"@0\n")?;
-        for sprite in &self.sprites {
-            for py in 0..SPRITE_SIZE {
-                for px in 0..SPRITE_SIZE {
-                    let p = sprite.pixels[(py * SPRITE_SIZE + px) as usize];
-                    write!(file, "{:04X}\n", p)?;
-                }
+        for block in self.pixels.chunks(SPRITE_DATA_SIZE as usize) {
+            for p in block {
+                write!(file, "{:04X}\n", p)?;
             }
             file.write(b"\n")?;
         }
         Ok(())
     }
 
+    // turns every pixel into the index of its colour in the palette
+    pub fn quantize(&mut self, palette: &Palette) {
+        for pixel in &mut self.pixels {
+            *pixel = palette.quantize(*pixel);
+        }
+    }
+
     // this will get a single corrsponding pixel
     pub fn get_sprite_word(&self, addr: u32) -> u16 {
-        return self.sprites[(addr / SPRITE_DATA_SIZE) as usize].pixels[(addr % SPRITE_DATA_SIZE) as usize];
+        return self.pixels[addr as usize];
     }
 
     pub fn set_sprite_word(&mut self, addr: u32, data: u16) {
-        self.sprites[(addr / SPRITE_DATA_SIZE) as usize].pixels[(addr % SPRITE_DATA_SIZE) as usize] = data;
+        self.pixels[addr as usize] = data;
+    }
+
+    // a pixel of a sprite, sprite memory wraps around
+    pub fn get_sprite_pixel(&self, sprite: &Sprite, x: u32, y: u32) -> u16 {
+        let addr = u32::from(sprite.pointer) + x + y * sprite.size();
+        self.pixels[addr as usize % self.pixels.len()]
+    }
+
+    // returns the x, y, control or pointer word of the sprite corresponding to addr/4, addr%4
+    pub fn get_sprite_entry(&self, addr: u32) -> u16 {
+        let sprite = &self.sprites[(addr / 4) as usize];
+        match addr % 4 {
+            0 => sprite.x,
+            1 => sprite.y,
+            2 => sprite.control,
+            _ => sprite.pointer,
+        }
+    }
+
+    pub fn set_sprite_entry(&mut self, addr: u32, data: u16) {
+        let sprite = &mut self.sprites[(addr / 4) as usize];
+        match addr % 4 {
+            0 => sprite.x = data,
+            1 => sprite.y = data,
+            2 => sprite.control = data,
+            _ => sprite.pointer = data,
+        }
     }
 
     // returns the either y or x coordinate of the sprite corresponding to the addr/2, addr%2
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+
+use crate::symbols::SymbolTable;
+
+// stages are fetch, decode, execute, memory and writeback
+const STAGES : u64 = 5;
+// stages before execute
+const EXECUTE_STAGE : u64 = 2;
+// the flags are tracked like a ninth register
+const FLAGS : usize = 8;
+// instructions listed in the report
+const REPORT_LENGTH : usize = 20;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum StallReason {
+    LoadUse,    // waiting on a value that is still being loaded
+    DataHazard, // waiting on a value that hasn't been written back
+    Branch,     // instructions fetched after a taken branch were flushed
+}
+
+// Simulates the hardware pipeline alongside the emulator. Every executed
+// instruction is given the cycle it enters execute: one after the previous
+// instruction, or later if one of its operands isn't ready yet or the
+// previous instruction was a taken branch resolved in execute.
+pub struct Pipeline {
+    report_path: String,
+    forwarding: bool,
+    branch_penalty: u64,
+    // cycle the last instruction entered execute
+    execute_cycle: u64,
+    last_taken: bool,
+    // earliest cycle an instruction reading each register can enter execute
+    ready: [u64; 9],
+    // whether that register is waiting on a load
+    loading: [bool; 9],
+    instructions: u64,
+    stalls: HashMap<StallReason, u64>,
+    per_pc: HashMap<u16, PcStats>,
+}
+
+#[derive(Default)]
+struct PcStats {
+    count: u64,
+    load_use: u64,
+    data_hazard: u64,
+    branch: u64,
+}
+
+// registers an instruction reads and writes
+struct Operands {
+    reads: Vec<usize>,
+    writes: Vec<usize>,
+    load: bool,
+}
+
+impl Pipeline {
+    pub fn new(report_path: &str, forwarding: bool, branch_penalty: u64) -> Pipeline {
+        Pipeline {
+            report_path: report_path.to_string(),
+            forwarding,
+            branch_penalty,
+            execute_cycle: EXECUTE_STAGE,
+            last_taken: false,
+            ready: [0; 9],
+            loading: [false; 9],
+            instructions: 0,
+            stalls: HashMap::new(),
+            per_pc: HashMap::new(),
+        }
+    }
+
+    // feeds one executed instruction through the pipeline
+    pub fn issue(&mut self, pc: u16, instr: u16, taken: bool) {
+        let operands = Operands::decode(instr);
+
+        // the first cycle without a hazard
+        let mut cycle = if self.instructions == 0 { self.execute_cycle } else { self.execute_cycle + 1 };
+        let mut stalls: Vec<(StallReason, u64)> = vec![];
+        if self.last_taken && self.branch_penalty > 0 {
+            cycle += self.branch_penalty;
+            stalls.push((StallReason::Branch, self.branch_penalty));
+        }
+
+        // wait for the operand that is ready last
+        let unstalled = cycle;
+        let mut hazard = StallReason::DataHazard;
+        for &register in &operands.reads {
+            if self.ready[register] > cycle {
+                cycle = self.ready[register];
+                hazard = if self.loading[register] { StallReason::LoadUse } else { StallReason::DataHazard };
+            }
+        }
+        if cycle > unstalled {
+            stalls.push((hazard, cycle - unstalled));
+        }
+
+        // when a result can be used by an instruction in execute
+        let ready = match (self.forwarding, operands.load) {
+            (true, false) => cycle + 1, // forwarded from the end of execute
+            (true, true) => cycle + 2,  // forwarded from the end of memory
+            // written back two stages later and read in decode the same cycle
+            (false, _) => cycle + 3,
+        };
+        for &register in &operands.writes {
+            self.ready[register] = ready;
+            self.loading[register] = operands.load;
+        }
+
+        let stats = self.per_pc.entry(pc).or_default();
+        stats.count += 1;
+        for (reason, stall) in stalls {
+            *self.stalls.entry(reason).or_insert(0) += stall;
+            match reason {
+                StallReason::LoadUse => stats.load_use += stall,
+                StallReason::DataHazard => stats.data_hazard += stall,
+                StallReason::Branch => stats.branch += stall,
+            }
+        }
+
+        self.execute_cycle = cycle;
+        self.last_taken = taken;
+        self.instructions += 1;
+    }
+
+    // cycles until the last instruction leaves writeback
+    pub fn cycles(&self) -> u64 {
+        if self.instructions == 0 {
+            return 0;
+        }
+        self.execute_cycle + (STAGES - EXECUTE_STAGE)
+    }
+
+    pub fn write_report(&self, symbols: &SymbolTable) -> Result<(), io::Error> {
+        let mut file = File::create(&self.report_path)?;
+        let stall = |reason| self.stalls.get(&reason).copied().unwrap_or(0);
+        writeln!(file, "pipeline: {} stages, forwarding {}, branch penalty {}",
+            STAGES, if self.forwarding { "on" } else { "off" }, self.branch_penalty)?;
+        writeln!(file, "instructions: {}", self.instructions)?;
+        writeln!(file, "cycles: {}", self.cycles())?;
+        if self.instructions > 0 {
+            writeln!(file, "CPI: {:.3}", self.cycles() as f64 / self.instructions as f64)?;
+        }
+        writeln!(file, "stall cycles:")?;
+        writeln!(file, "  load-use: {}", stall(StallReason::LoadUse))?;
+        writeln!(file, "  data hazard: {}", stall(StallReason::DataHazard))?;
+        writeln!(file, "  branch: {}", stall(StallReason::Branch))?;
+
+        let mut stalled: Vec<(&u16, &PcStats)> = self.per_pc.iter()
+            .filter(|(_, stats)| stats.stalls() > 0)
+            .collect();
+        stalled.sort_by_key(|(pc, stats)| (std::cmp::Reverse(stats.stalls()), **pc));
+        writeln!(file, "\nmost stalled instructions:")?;
+        writeln!(file, "  {:>12}{:>12}{:>12}{:>12}  pc", "executed", "load-use", "data", "branch")?;
+        for (pc, stats) in stalled.iter().take(REPORT_LENGTH) {
+            writeln!(file, "  {:>12}{:>12}{:>12}{:>12}  {}",
+                stats.count, stats.load_use, stats.data_hazard, stats.branch, symbols.describe(**pc))?;
+        }
+        Ok(())
+    }
+}
+
+impl PcStats {
+    fn stalls(&self) -> u64 {
+        self.load_use + self.data_hazard + self.branch
+    }
+}
+
+impl Operands {
+    fn decode(instr: u16) -> Operands {
+        let r_a = usize::from((instr >> 10) & 0b111);
+        let r_b = usize::from((instr >> 7) & 0b111);
+        let r_c = usize::from(instr & 0b111);
+        let mut operands = Operands { reads: vec![], writes: vec![], load: false };
+        match instr >> 13 {
+            0 => {
+                let op = (instr >> 3) & 0b1111;
+                // ops from not onwards only use r_c
+                if op < 8 {
+                    operands.reads.push(r_b);
+                }
+                operands.reads.push(r_c);
+                // addc, subc, shrc and shlc use the carry flag
+                if matches!(op, 2 | 4 | 14 | 15) {
+                    operands.reads.push(FLAGS);
+                }
+                operands.writes.extend([r_a, FLAGS]);
+            },
+            1 => {
+                operands.reads.push(r_b);
+                operands.writes.extend([r_a, FLAGS]);
+            },
+            3 => operands.writes.push(r_a),
+            4 => operands.reads.extend([r_a, r_b]),
+            5 => {
+                operands.reads.push(r_b);
+                operands.writes.push(r_a);
+                operands.load = true;
+            },
+            // everything but jmp looks at the flags
+            6 if (instr >> 7) & 0b111111 != 6 => operands.reads.push(FLAGS),
+            7 => {
+                if instr & 0x007F == 0 {
+                    operands.reads.push(r_b);
+                    operands.writes.push(r_a);
+                } else {
+                    // exit and putchar use r3
+                    operands.reads.push(3);
+                }
+            },
+            _ => {}
+        }
+        // r0 is always zero and never waits on anything
+        operands.reads.retain(|&register| register != 0);
+        operands.writes.retain(|&register| register != 0);
+        operands
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::symbols::SymbolTable;
+
+// entries listed in each table of the report
+const REPORT_LENGTH : usize = 20;
+// width of the address ranges cycles are bucketed into
+const RANGE_SIZE : usize = 0x100;
+
+// Counts where the program spends its cycles. Calls and returns are
+// reconstructed from jalr: one that saves a return address is a call,
+// and one that jumps to a saved return address is the matching return.
+// Cycles are charged to a node of the call tree, so a function's cycles
+// can be split by the path that called it.
+pub struct Profiler {
+    report_path: String,
+    executions: Vec<u64>, // per pc
+    cycles: Vec<u64>,     // per pc
+    nodes: Vec<CallNode>,
+    current: usize,
+    // return address and caller of every active call
+    stack: Vec<(u16, usize)>,
+    calls: HashMap<u16, u64>, // per function
+}
+
+struct CallNode {
+    function: u16, // entry address
+    parent: usize,
+    children: HashMap<u16, usize>,
+    cycles: u64,   // spent in this function itself along this path
+}
+
+#[derive(Default, Clone, Copy)]
+struct FunctionStats {
+    calls: u64,
+    inclusive: u64,
+    exclusive: u64,
+}
+
+impl Profiler {
+    pub fn new(report_path: &str) -> Profiler {
+        Profiler {
+            report_path: report_path.to_string(),
+            executions: vec![0; 1 << 16],
+            cycles: vec![0; 1 << 16],
+            // the program itself starts at address 0
+            nodes: vec![CallNode { function: 0, parent: 0, children: HashMap::new(), cycles: 0 }],
+            current: 0,
+            stack: vec![],
+            calls: HashMap::new(),
+        }
+    }
+
+    // records an instruction at pc that took cycles and left the pc at next_pc
+    pub fn record(&mut self, pc: u16, instr: u16, cycles: u64, next_pc: u16) {
+        self.executions[usize::from(pc)] += 1;
+        self.cycles[usize::from(pc)] += cycles;
+        self.nodes[self.current].cycles += cycles;
+
+        let is_jalr = instr >> 13 == 7 && instr & 0x007F == 0;
+        if !is_jalr {
+            return;
+        }
+        let r_a = (instr >> 10) & 0b111;
+        if r_a != 0 {
+            self.call(pc.wrapping_add(1), next_pc);
+        } else if let Some(depth) = self.stack.iter().rposition(|&(ret, _)| ret == next_pc) {
+            // returns past frames that never returned themselves, like longjmp
+            self.current = self.stack[depth].1;
+            self.stack.truncate(depth);
+        }
+    }
+
+    fn call(&mut self, ret: u16, function: u16) {
+        self.stack.push((ret, self.current));
+        *self.calls.entry(function).or_insert(0) += 1;
+        let next = self.nodes.len();
+        let child = *self.nodes[self.current].children.entry(function).or_insert(next);
+        if child == next {
+            self.nodes.push(CallNode { function, parent: self.current, children: HashMap::new(), cycles: 0 });
+        }
+        self.current = child;
+    }
+
+    // functions from the root down to a node
+    fn path(&self, mut node: usize) -> Vec<u16> {
+        let mut path = vec![self.nodes[node].function];
+        while node != 0 {
+            node = self.nodes[node].parent;
+            path.push(self.nodes[node].function);
+        }
+        path.reverse();
+        path
+    }
+
+    fn function_name(function: u16, symbols: &SymbolTable) -> String {
+        match symbols.name(function) {
+            Some(name) => name.to_string(),
+            None => format!("{:#06x}", function),
+        }
+    }
+
+    fn function_stats(&self) -> HashMap<u16, FunctionStats> {
+        let mut stats: HashMap<u16, FunctionStats> = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            stats.entry(node.function).or_default().exclusive += node.cycles;
+            // recursive functions only count once towards their inclusive cycles
+            let functions: HashSet<u16> = self.path(i).into_iter().collect();
+            for function in functions {
+                stats.entry(function).or_default().inclusive += node.cycles;
+            }
+        }
+        for (function, calls) in &self.calls {
+            stats.entry(*function).or_default().calls = *calls;
+        }
+        stats
+    }
+
+    pub fn write_report(&self, symbols: &SymbolTable) -> Result<(), io::Error> {
+        let mut file = File::create(&self.report_path)?;
+        let total: u64 = self.cycles.iter().sum();
+        let percent = |cycles: u64| 100.0 * cycles as f64 / total.max(1) as f64;
+        writeln!(file, "total cycles: {}", total)?;
+
+        let mut functions: Vec<(u16, FunctionStats)> = self.function_stats().into_iter().collect();
+        functions.sort_by_key(|&(function, stats)| (std::cmp::Reverse(stats.exclusive), function));
+        writeln!(file, "\nhottest functions:")?;
+        writeln!(file, "  {:>10}{:>12}{:>8}{:>12}{:>8}  function", "calls", "exclusive", "%", "inclusive", "%")?;
+        for (function, stats) in functions.iter().take(REPORT_LENGTH) {
+            writeln!(file, "  {:>10}{:>12}{:>8.2}{:>12}{:>8.2}  {}",
+                stats.calls, stats.exclusive, percent(stats.exclusive),
+                stats.inclusive, percent(stats.inclusive),
+                Self::function_name(*function, symbols))?;
+        }
+
+        let mut addresses: Vec<usize> = (0..self.cycles.len()).filter(|&pc| self.cycles[pc] > 0).collect();
+        addresses.sort_by_key(|&pc| (std::cmp::Reverse(self.cycles[pc]), pc));
+        writeln!(file, "\nhottest instructions:")?;
+        writeln!(file, "  {:>12}{:>12}{:>8}  pc", "executed", "cycles", "%")?;
+        for &pc in addresses.iter().take(REPORT_LENGTH) {
+            writeln!(file, "  {:>12}{:>12}{:>8.2}  {}",
+                self.executions[pc], self.cycles[pc], percent(self.cycles[pc]), symbols.describe(pc as u16))?;
+        }
+
+        writeln!(file, "\ncycles by address range:")?;
+        for (i, range) in self.cycles.chunks(RANGE_SIZE).enumerate() {
+            let cycles: u64 = range.iter().sum();
+            if cycles > 0 {
+                let start = i * RANGE_SIZE;
+                writeln!(file, "  {:#06x}-{:#06x}{:>12}{:>8.2}", start, start + RANGE_SIZE - 1, cycles, percent(cycles))?;
+            }
+        }
+
+        self.write_folded_stacks(symbols)
+    }
+
+    // one line per call path: the functions separated by ; and the cycles spent at the end of it
+    fn write_folded_stacks(&self, symbols: &SymbolTable) -> Result<(), io::Error> {
+        let mut path = PathBuf::from(&self.report_path);
+        path.set_extension("folded");
+        let mut file = File::create(path)?;
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.cycles == 0 {
+                continue;
+            }
+            let names: Vec<String> = self.path(i).into_iter().map(|function| Self::function_name(function, symbols)).collect();
+            writeln!(file, "{} {}", names.join(";"), node.cycles)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use std::fs;
+use std::path::Path;
+
+// Names for addresses in the program, read from a symbol file next to
+// the binary (program.sym). Each line holds a name and an address, as
+//   draw_sprite = 0x0120
+//   draw_sprite: 0x0120
+//   0x0120 draw_sprite
+// An optional source map (program.lines) gives the source line of an
+// address, one "0x0120 game.c:142" per line. Both take # comments.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<(u16, String)>,           // sorted by address
+    lines: Vec<(u16, String, u32)>,        // sorted by address
+}
+
+impl SymbolTable {
+    // loads the symbol file and source map next to a binary, if there are any
+    pub fn load_for(program: &str) -> Result<SymbolTable, String> {
+        let mut table = SymbolTable::default();
+        let symbols = Path::new(program).with_extension("sym");
+        if symbols.exists() {
+            table.load_symbols(symbols.to_str().unwrap())?;
+        }
+        let lines = Path::new(program).with_extension("lines");
+        if lines.exists() {
+            table.load_source_map(lines.to_str().unwrap())?;
+        }
+        Ok(table)
+    }
+
+    pub fn load_symbols(&mut self, path: &str) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(|c: char| c == '=' || c == ':' || c.is_whitespace())
+                .filter(|field| !field.is_empty())
+                .collect();
+            let symbol = match fields.as_slice() {
+                [a, b] => match (parse_address(a), parse_address(b)) {
+                    (_, Some(address)) => (address, a.to_string()),
+                    (Some(address), None) => (address, b.to_string()),
+                    (None, None) => return Err(format!("{path}: line {}: no address", i + 1)),
+                },
+                _ => return Err(format!("{path}: line {}: expected a name and an address", i + 1)),
+            };
+            self.symbols.push(symbol);
+        }
+        self.symbols.sort();
+        Ok(())
+    }
+
+    pub fn load_source_map(&mut self, path: &str) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let entry = line.split_once(char::is_whitespace)
+                .and_then(|(address, location)| {
+                    let (file, number) = location.trim().rsplit_once(':')?;
+                    Some((parse_address(address)?, file.to_string(), number.parse().ok()?))
+                })
+                .ok_or(format!("{path}: line {}: expected an address and file:line", i + 1))?;
+            self.lines.push(entry);
+        }
+        self.lines.sort();
+        Ok(())
+    }
+
+    pub fn has_source_lines(&self) -> bool {
+        !self.lines.is_empty()
+    }
+
+    // every symbol with its address, in address order
+    pub fn symbols(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.symbols.iter().map(|(address, name)| (*address, name.as_str()))
+    }
+
+    // every source map entry with the address its code starts at, in address order
+    pub fn source_lines(&self) -> impl Iterator<Item = (u16, &str, u32)> {
+        self.lines.iter().map(|(address, file, line)| (*address, file.as_str(), *line))
+    }
+
+    // the symbol at or before an address and how far past it the address is
+    pub fn lookup(&self, address: u16) -> Option<(&str, u16)> {
+        let i = self.symbols.partition_point(|(start, _)| *start <= address);
+        let (start, name) = self.symbols.get(i.checked_sub(1)?)?;
+        Some((name, address - start))
+    }
+
+    // the exact symbol at an address
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.lookup(address).filter(|(_, offset)| *offset == 0).map(|(name, _)| name)
+    }
+
+    pub fn source_line(&self, address: u16) -> Option<(&str, u32)> {
+        let i = self.lines.partition_point(|(start, _, _)| *start <= address);
+        let (_, file, line) = self.lines.get(i.checked_sub(1)?)?;
+        Some((file, *line))
+    }
+
+    // an address with whatever is known about it, like
+    // 0x0123 in draw_sprite (game.c:142) or 0x0123 in draw_sprite+0x3
+    pub fn describe(&self, address: u16) -> String {
+        let mut description = format!("{:#06x}", address);
+        match (self.lookup(address), self.source_line(address)) {
+            (Some((name, _)), Some((file, line))) => description += &format!(" in {name} ({file}:{line})"),
+            (Some((name, 0)), None) => description += &format!(" in {name}"),
+            (Some((name, offset)), None) => description += &format!(" in {name}+{offset:#x}"),
+            (None, Some((file, line))) => description += &format!(" ({file}:{line})"),
+            (None, None) => {}
+        }
+        description
+    }
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
@@ -4,6 +4,17 @@ use super::*;
 
 const DATA_PATH: &str = "../data";
 
+// loads a program from its words, through a file that is removed once it is read
+#[cfg(test)]
+fn program(name: &str, words: &[u16]) -> Emulator {
+  let path = std::env::temp_dir().join(format!("jpeb_{}.bin", name));
+  let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+  std::fs::write(&path, bytes).unwrap();
+  let cpu = Emulator::new(path.to_str().unwrap(), DATA_PATH);
+  std::fs::remove_file(&path).unwrap();
+  cpu
+}
+
 #[test]
 fn addi_test() {
   let cpu = Emulator::new("../tests/bin/addi_test.bin", DATA_PATH);
@@ -280,4 +291,748 @@ fn load_test() {
   let cpu = Emulator::new("../tests/bin/load_test.bin", DATA_PATH);
   let result = cpu.run(false);
   assert_eq!(result, 0x0FFF);
-}
\ No newline at end of file
+}
+
+#[test]
+fn cycle_limit_test() {
+  let mut cpu = Emulator::new("../tests/bin/infinite_loop_test.bin", DATA_PATH);
+  cpu.set_max_cycles(Some(1000));
+  match cpu.run_until_stop(false) {
+    StopReason::CycleLimit(state) => {
+      assert_eq!(state.cycle_count, 1000);
+      assert!(!state.trace.is_empty());
+    },
+    reason => panic!("expected the cycle limit to be hit, got {}", reason),
+  }
+}
+
+#[test]
+fn idle_test() {
+  // jmp -1 with no interrupts enabled can never end without a window
+  let mut cpu = program("idle_test", &[0xC37F]);
+  cpu.set_max_cycles(Some(10_000));
+  match cpu.run_until_stop(false) {
+    StopReason::Idle(state) => assert_eq!(state.pc, 0),
+    reason => panic!("expected the program to be idle, got {}", reason),
+  }
+
+  // lui r2, 0x3FF then poll the empty keyboard at 0xFFFF: lw r3, r2, 0x3F; jmp -2
+  let mut cpu = program("polling_idle_test", &[0x6BFF, 0xAD3F, 0xC37E]);
+  cpu.set_max_cycles(Some(10_000));
+  match cpu.run_until_stop(false) {
+    StopReason::Idle(state) => assert_eq!(state.pc, 2), // after the lw
+    reason => panic!("expected the program to be idle, got {}", reason),
+  }
+}
+
+#[test]
+fn polling_threshold_test() {
+  let mut memory = memory::Memory::new(vec![0xE070], DATA_PATH);
+  let poll = |memory: &mut memory::Memory| {
+    memory.fetch(0);
+    memory.read(0xFFFF);
+    memory.is_polling_idle()
+  };
+  // 256 empty reads of the keyboard in a row
+  for _ in 0..255 {
+    assert!(!poll(&mut memory));
+  }
+  assert!(poll(&mut memory));
+  assert!(poll(&mut memory));
+
+  // only the instructions that read the keyboard count as waiting on it
+  memory.fetch(0);
+  assert!(!memory.is_polling_idle());
+  memory.fetch(0);
+  memory.read(0x9000);
+  assert!(!memory.is_polling_idle());
+
+  // a store or a key starts the count again
+  memory.write(0x9000, 1);
+  assert!(!poll(&mut memory));
+  for _ in 0..255 {
+    poll(&mut memory);
+  }
+  memory.get_io_buffer().write().unwrap().push_back(0x41);
+  assert!(!poll(&mut memory));
+  assert!(!poll(&mut memory));
+}
+
+#[test]
+fn timing_model_test() {
+  let config = config::Config::parse("[cycles]\nload = 3\nbranch_taken = 2\n[wait_states]\n\"0xC000-0xDFFF\" = 1\n").unwrap();
+  let mut timing = timing::TimingModel::default();
+  timing.configure(config.section("cycles"), config.section("wait_states")).unwrap();
+  assert_eq!(timing.cycles(0xA000, 0x0010, Some(0x0100), false), 3); // lw from ram
+  assert_eq!(timing.cycles(0xA000, 0x0010, Some(0xC000), false), 4); // lw from the tile map
+  assert_eq!(timing.cycles(0xC37F, 0x0010, None, true), 2); // taken jmp
+  assert_eq!(timing.cycles(0xC07F, 0x0010, None, false), 1); // bz not taken
+}
+
+#[test]
+fn pipeline_load_use_test() {
+  // lw r3, r1, 0 followed by add r4, r3, r3
+  let mut forwarding = pipeline::Pipeline::new("/dev/null", true, 2);
+  forwarding.issue(0, 0xAC80, false);
+  forwarding.issue(1, 0x118B, false);
+  assert_eq!(forwarding.cycles(), 7); // 6 plus a load-use stall
+
+  let mut no_forwarding = pipeline::Pipeline::new("/dev/null", false, 2);
+  no_forwarding.issue(0, 0xAC80, false);
+  no_forwarding.issue(1, 0x118B, false);
+  assert_eq!(no_forwarding.cycles(), 8);
+}
+
+#[test]
+fn profiler_call_graph_test() {
+  let report = std::env::temp_dir().join("jpeb_profile_test.txt");
+  let mut profiler = profiler::Profiler::new(report.to_str().unwrap());
+  profiler.record(0x0000, 0x0000, 1, 0x0001);
+  profiler.record(0x0001, 0xFC00, 1, 0x0010); // jalr r7, r0: call 0x0010
+  profiler.record(0x0010, 0x0000, 3, 0x0011);
+  profiler.record(0x0011, 0xE380, 1, 0x0002); // jalr r0, r7: return
+  profiler.record(0x0002, 0x0000, 1, 0x0003);
+  profiler.write_report(&symbols::SymbolTable::default()).unwrap();
+
+  let folded = std::fs::read_to_string(report.with_extension("folded")).unwrap();
+  assert_eq!(folded, "0x0000 3\n0x0000;0x0010 4\n");
+}
+
+#[test]
+fn symbols_test() {
+  let dir = std::env::temp_dir();
+  let symbols_path = dir.join("jpeb_symbols_test.sym");
+  let lines_path = dir.join("jpeb_symbols_test.lines");
+  std::fs::write(&symbols_path, "main = 0x0000\ndraw_sprite: 0x0120\n0x0200 update # comment\n").unwrap();
+  std::fs::write(&lines_path, "0x0120 game.c:140\n0x0123 game.c:142\n").unwrap();
+
+  let mut symbols = symbols::SymbolTable::default();
+  symbols.load_symbols(symbols_path.to_str().unwrap()).unwrap();
+  assert_eq!(symbols.describe(0x0003), "0x0003 in main+0x3");
+  assert_eq!(symbols.name(0x0200), Some("update"));
+  symbols.load_source_map(lines_path.to_str().unwrap()).unwrap();
+  assert_eq!(symbols.describe(0x0124), "0x0124 in draw_sprite (game.c:142)");
+}
+
+#[test]
+fn coverage_listing_test() {
+  let report = std::env::temp_dir().join("jpeb_coverage_test.txt");
+  let mut coverage = coverage::Coverage::new(report.to_str().unwrap());
+  coverage.record(0x0000, 0x0000, false);
+  coverage.record(0x0001, 0xC001, true); // bz +1
+  coverage.record(0x0003, 0x0000, false);
+  coverage.write_report(&symbols::SymbolTable::default(), 4).unwrap();
+
+  let listing = std::fs::read_to_string(report).unwrap();
+  assert!(listing.contains("0x0000-0x0001 executed\n0x0002-0x0002 not executed\n0x0003-0x0003 executed\n"));
+  assert!(listing.contains("0x0001: taken 1, not taken 0"));
+}
+
+#[test]
+fn write_watchpoint_test() {
+  let mut cpu = Emulator::new("../tests/bin/sw_lw_test.bin", DATA_PATH);
+  cpu.add_watchpoint(memory::Watchpoint { kind: memory::WatchKind::Write, range: 0x0000..=0xFFFF });
+  match cpu.run_until_stop(false) {
+    StopReason::Watchpoint(hit, state) => {
+      assert_eq!(hit.kind, memory::WatchKind::Write);
+      assert_eq!(state.trace.last().unwrap().pc, state.pc);
+    },
+    reason => panic!("expected the store to hit the watchpoint, got {}", reason),
+  }
+}
+
+#[test]
+fn stack_limit_test() {
+  // addi r1, r0, 63 then push zeros forever: addi r1, r1, -1; sw r0, r1, 0; jmp -3
+  let mut cpu = program("stack_limit_test", &[0x243F, 0x24FF, 0x8080, 0xC37D]);
+  cpu.set_stack_limit(Some(0x0030));
+  cpu.set_max_cycles(Some(10_000));
+  match cpu.run_until_stop(false) {
+    StopReason::StackOverflow(address, state) => {
+      assert_eq!(address, 0x002F);
+      assert_eq!(state.pc, 2);
+    },
+    reason => panic!("expected a stack overflow, got {}", reason),
+  }
+}
+
+#[test]
+fn uninitialized_read_test() {
+  // lui r2, 0x240 then lw r3, r2, 0 from 0x9000, past anything mem.hex sets, then sys EXIT
+  let mut cpu = program("uninitialized_read_test", &[0x6A40, 0xAD00, 0xE070]);
+  cpu.set_sanitizer(Some(emulator::Sanitizer::Trap));
+  match cpu.run_until_stop(false) {
+    StopReason::UninitializedRead(address, state) => {
+      assert_eq!(address, 0x9000);
+      assert_eq!(state.pc, 1);
+    },
+    reason => panic!("expected an uninitialized read, got {}", reason),
+  }
+}
+
+#[test]
+fn code_protection_test() {
+  // sw r0, r0, 0 over the first instruction, then sys EXIT
+  let words = [0x8000, 0xE070];
+
+  let mut cpu = program("code_protection_test", &words);
+  cpu.protect_code(vec![]);
+  match cpu.run_until_stop(false) {
+    StopReason::ProtectionFault(address, state) => {
+      assert_eq!(address, 0x0000);
+      assert_eq!(state.pc, 0);
+    },
+    reason => panic!("expected a protection fault, got {}", reason),
+  }
+
+  let mut cpu = program("code_protection_test", &words);
+  cpu.protect_code(vec![0x0000..=0x0000]);
+  assert_eq!(cpu.run(false), 0);
+}
+
+#[test]
+fn bus_test() {
+  use std::sync::{Arc, RwLock};
+  let scroll = Arc::new(RwLock::new(0));
+  let mut bus = bus::Bus::new(vec![
+    (0xF000..=0xF000, Box::new(devices::Uart) as Box<dyn bus::Device>),
+    (0xFFFE..=0xFFFE, Box::new(devices::Register::new("scroll", Arc::clone(&scroll)))),
+  ]).unwrap();
+  assert!(bus.write(0xFFFE, 7));
+  assert_eq!(*scroll.read().unwrap(), 7);
+  assert_eq!(bus.read(0xFFFE), Some(7));
+  assert_eq!(bus.read(0x1234), None);
+  assert!(!bus.write(0x1234, 1));
+
+  let overlap = bus.map(0xEFFF..=0xF001, Box::new(devices::Register::new("overlap", Arc::new(RwLock::new(0)))));
+  assert!(overlap.is_err());
+  assert!(bus.map(0xF001..=0xF001, Box::new(devices::Register::new("next", Arc::new(RwLock::new(0))))).is_ok());
+}
+
+#[test]
+fn machine_config_test() {
+  let config = config::Config::parse("[data]\nmem_hex = \"rev2/mem.hex\"\n[devices]\ntile_map = \"0x8000-0x9FFF\"\nuart = 0xF100\n[registers]\nr1 = 0x7FFF\npc = 0x10\n").unwrap();
+  let mut machine = machine::Machine::standard(DATA_PATH);
+  machine.configure(&config).unwrap();
+  assert_eq!(machine.mem_hex, "rev2/mem.hex");
+  assert_eq!(machine.devices, vec![(machine::DeviceKind::TileMap, 0x8000..=0x9FFF), (machine::DeviceKind::Uart, 0xF100..=0xF100)]);
+  assert_eq!(machine.registers[1], Some(0x7FFF));
+  assert_eq!(machine.pc, 0x10);
+
+  let overlap = config::Config::parse("[devices]\ntile_map = 0xC000\nframe_buffer = 0xD000\n").unwrap();
+  assert!(machine::Machine::standard(DATA_PATH).configure(&overlap).is_err());
+}
+
+#[test]
+fn memory_layout_test() {
+  let mut machine = machine::Machine::standard(DATA_PATH);
+  assert!(machine.validate(0x100).is_ok());
+  // the old default, with the stack starting on the first word of the sprite map
+  machine.stack_start = 0xA000;
+  assert!(machine.validate(0x100).unwrap_err().contains("sprite_map"));
+}
+
+#[test]
+fn write_through_test() {
+  use std::sync::{Arc, RwLock};
+  let mut bus = bus::Bus::new(vec![
+    (0xFFFE..=0xFFFE, Box::new(devices::Register::new("scroll", Arc::new(RwLock::new(0)))) as Box<dyn bus::Device>),
+  ]).unwrap();
+  assert!(!bus.writes_through(0xFFFE));
+  bus.set_write_through(0xFFFE, true);
+  assert!(bus.writes_through(0xFFFE));
+  assert!(!bus.writes_through(0x1234));
+}
+
+#[test]
+fn vblank_interrupt_test() {
+  // point the vector at 6, enable the vblank interrupt and spin until it comes,
+  // then exit with the frame counter
+  let mut cpu = program("vblank_interrupt_test", &[
+    0x6BC0,    // lui r2, 0x3C0 (0xF000)
+    0x2C06,    // addi r3, r0, 6
+    0x8D12,    // sw r3, r2, 0x12 (vector)
+    0x2C01,    // addi r3, r0, 1
+    0x8D10,    // sw r3, r2, 0x10 (enable)
+    0xC37F,    // jmp -1
+    0xAD21,    // lw r3, r2, 0x21 (frame counter)
+    0xE070,    // sys EXIT
+  ]);
+  cpu.set_frame_cycles(1050);
+  cpu.set_max_cycles(Some(10_000));
+  assert_eq!(cpu.run(false), 1);
+}
+
+#[test]
+fn line_compare_interrupt_test() {
+  // interrupt as the beam leaves line 10 and exit with the scanline the handler sees
+  let mut cpu = program("line_compare_interrupt_test", &[
+    0x6BC0,    // lui r2, 0x3C0 (0xF000)
+    0x2C0A,    // addi r3, r0, 10
+    0x8D23,    // sw r3, r2, 0x23 (line compare)
+    0x2C02,    // addi r3, r0, 2
+    0x8D11,    // sw r3, r2, 0x11 (acknowledge the lines before)
+    0x300A,    // addi r4, r0, 10
+    0x9112,    // sw r4, r2, 0x12 (vector)
+    0x8D10,    // sw r3, r2, 0x10 (enable)
+    0xC37F,    // jmp -1
+    0x0000,
+    0xAD22,    // lw r3, r2, 0x22 (scanline)
+    0xE070,    // sys EXIT
+  ]);
+  cpu.set_frame_cycles(1050); // two cycles a line
+  cpu.set_max_cycles(Some(10_000));
+  assert_eq!(cpu.run(false), 11);
+}
+
+#[test]
+fn palette_test() {
+  use std::sync::{Arc, RwLock};
+  use bus::Device;
+  let mut colors = vec![0x0000; 256];
+  colors[1] = 0x000F; // red
+  colors[2] = 0x0F00; // blue
+  colors[3] = 0xFFFF; // transparent
+  let palette = memory::Palette { colors, indexed_tiles: vec![false; 128], indexed_sprites: vec![false; 32] };
+  assert_eq!(palette.quantize(0x000F), 1);
+  assert_eq!(palette.quantize(0x010E), 1);
+  assert_eq!(palette.quantize(0x0E01), 2);
+  assert_eq!(palette.quantize(0xF000), 3);
+  assert_eq!(palette.resolve(0x0102, true), 0x0F00);
+  assert_eq!(palette.resolve(0x0102, false), 0x0102);
+
+  let palette = Arc::new(RwLock::new(palette));
+  let mut ram = devices::PaletteRam::new(Arc::clone(&palette));
+  ram.write(4, 0x00F0);
+  assert_eq!(ram.read(4), 0x00F0);
+  let mut modes = devices::PaletteModes::new(Arc::clone(&palette));
+  modes.write(1, 0x8001);
+  modes.write(8, 0x0004);
+  assert!(palette.read().unwrap().indexed_tiles[16]);
+  assert!(palette.read().unwrap().indexed_tiles[31]);
+  assert!(!palette.read().unwrap().indexed_tiles[17]);
+  assert!(palette.read().unwrap().indexed_sprites[2]);
+  assert_eq!(modes.read(1), 0x8001);
+  assert_eq!(modes.read(8), 0x0004);
+}
+
+// video memory with nothing in it, for tests to fill in what they look at
+#[cfg(test)]
+fn blank_video() -> video::VideoMemory {
+  use std::sync::{Arc, RwLock};
+  let palette = memory::Palette::from_maps(&memory::TileMap::new(128), &memory::SpriteMap::new());
+  video::VideoMemory {
+    frame_buffer: Arc::new(RwLock::new(memory::FrameBuffer::new(memory::FRAME_WIDTH, memory::FRAME_HEIGHT))),
+    tile_map: Arc::new(RwLock::new(memory::TileMap::new(128))),
+    vscroll_register: Arc::new(RwLock::new(0)),
+    hscroll_register: Arc::new(RwLock::new(0)),
+    scale_register: Arc::new(RwLock::new(0)),
+    sprite_map: Arc::new(RwLock::new(memory::SpriteMap::new())),
+    palette: Arc::new(RwLock::new(palette)),
+    collisions: Arc::new(RwLock::new(memory::Collisions::new())),
+    video_mode_register: Arc::new(RwLock::new(memory::VIDEO_MODE_TILES)),
+    bitmap: Arc::new(RwLock::new(memory::Bitmap::new())),
+    console: Arc::new(RwLock::new(memory::Console::new())),
+  }
+}
+
+#[test]
+fn tile_attributes_test() {
+  use std::sync::{Arc, RwLock};
+  use ::image::Rgba;
+  let mut frame_buffer = memory::FrameBuffer::new(memory::FRAME_WIDTH, memory::FRAME_HEIGHT);
+  // the first tile flipped horizontally, the third above the sprites
+  frame_buffer.set_attribute_word(0, 0x0401);
+  assert_eq!(frame_buffer.get_attributes(0, 0), memory::TILE_HFLIP);
+  assert_eq!(frame_buffer.get_attributes(2, 0), memory::TILE_PRIORITY);
+  let mut tile_map = memory::TileMap::new(128);
+  tile_map.tiles[0].pixels[0] = 0x000F;
+  let mut sprite_map = memory::SpriteMap::new();
+  sprite_map.pixels[..32 * 32].fill(0x00F0);
+  sprite_map.sprites[0].x = 16;
+  sprite_map.sprites[0].y = 0;
+  let tile_map = Arc::new(RwLock::new(tile_map));
+  let sprite_map = Arc::new(RwLock::new(sprite_map));
+  let palette = memory::Palette::from_maps(&tile_map.read().unwrap(), &sprite_map.read().unwrap());
+  let video = video::VideoMemory {
+    frame_buffer: Arc::new(RwLock::new(frame_buffer)),
+    tile_map,
+    sprite_map,
+    palette: Arc::new(RwLock::new(palette)),
+    ..blank_video()
+  };
+  let frame = Arc::new(RwLock::new(video::Frame::new()));
+  let mut renderer = video::Renderer::new(video, Arc::clone(&frame));
+  renderer.render_line(0);
+  renderer.finish_frame(1);
+  let frame = frame.read().unwrap();
+  assert_eq!(*frame.pixels.get_pixel(0, 0), Rgba([0, 0, 0, 255]));
+  assert_eq!(*frame.pixels.get_pixel(7, 0), Rgba([240, 0, 0, 255]));
+  // the third tile's first pixel is drawn over the sprite, the sprite over the fourth tile
+  assert_eq!(*frame.pixels.get_pixel(16, 0), Rgba([240, 0, 0, 255]));
+  assert_eq!(*frame.pixels.get_pixel(24, 0), Rgba([0, 240, 0, 255]));
+
+  // the palette bit picks the upper half whatever the index
+  let mut palette = memory::Palette::from_maps(&memory::TileMap::new(128), &memory::SpriteMap::new());
+  palette.indexed_tiles[0] = true;
+  palette.colors[0x90] = 0x0123;
+  palette.colors[0xC8] = 0x0456;
+  assert_eq!(video::tile_color(&palette, 0, memory::TILE_PALETTE, 0x10), 0x0123);
+  assert_eq!(video::tile_color(&palette, 0, memory::TILE_PALETTE, 0xC8), 0x0456);
+}
+
+#[test]
+fn sprite_table_test() {
+  use std::sync::{Arc, RwLock};
+  use ::image::Rgba;
+  use bus::Device;
+  let size16 = 1 << memory::SPRITE_SIZE_SHIFT;
+  let mut frame_buffer = memory::FrameBuffer::new(memory::FRAME_WIDTH, memory::FRAME_HEIGHT);
+  frame_buffer.set_tile_pair(12, 0x0100); // tile 1 at 200-207
+  let mut tile_map = memory::TileMap::new(128);
+  tile_map.tiles[1].pixels.fill(0x0F00);
+  let mut sprite_map = memory::SpriteMap::new();
+  sprite_map.pixels[0] = 0x000F;
+  let sprite_map = Arc::new(RwLock::new(sprite_map));
+  // four 16x16 sprites showing the same pixels
+  let mut table = devices::SpriteTable::new(Arc::clone(&sprite_map));
+  for (n, x, control) in [(8, 0, memory::SPRITE_HFLIP), (9, 100, 0), (10, 200, memory::SPRITE_BEHIND), (11, 300, memory::SPRITE_BEHIND)] {
+    table.write(n * 4, x);
+    table.write(n * 4 + 1, 0);
+    table.write(n * 4 + 2, memory::SPRITE_ENABLE | size16 | control);
+    table.write(n * 4 + 3, 0);
+  }
+  assert_eq!(table.read(9 * 4), 100);
+  assert_eq!(sprite_map.read().unwrap().sprites[8].size(), 16);
+
+  let tile_map = Arc::new(RwLock::new(tile_map));
+  let palette = memory::Palette::from_maps(&tile_map.read().unwrap(), &sprite_map.read().unwrap());
+  let video = video::VideoMemory {
+    frame_buffer: Arc::new(RwLock::new(frame_buffer)),
+    tile_map,
+    sprite_map,
+    palette: Arc::new(RwLock::new(palette)),
+    ..blank_video()
+  };
+  let frame = Arc::new(RwLock::new(video::Frame::new()));
+  let mut renderer = video::Renderer::new(video, Arc::clone(&frame));
+  renderer.render_line(0);
+  renderer.finish_frame(1);
+  let frame = frame.read().unwrap();
+  let red = Rgba([240, 0, 0, 255]);
+  assert_eq!(*frame.pixels.get_pixel(15, 0), red);
+  assert_eq!(*frame.pixels.get_pixel(0, 0), Rgba([0, 0, 0, 255]));
+  assert_eq!(*frame.pixels.get_pixel(100, 0), red);
+  assert_eq!(*frame.pixels.get_pixel(200, 0), Rgba([0, 0, 240, 255]));
+  assert_eq!(*frame.pixels.get_pixel(300, 0), red);
+}
+
+#[test]
+fn collision_test() {
+  use std::sync::{Arc, RwLock};
+  use bus::Device;
+  let mut frame_buffer = memory::FrameBuffer::new(memory::FRAME_WIDTH, memory::FRAME_HEIGHT);
+  frame_buffer.set_tile_pair(12, 0x0100); // tile 1 at 200-207
+  let mut tile_map = memory::TileMap::new(128);
+  tile_map.tiles[1].pixels.fill(0x0F00);
+  let mut sprite_map = memory::SpriteMap::new();
+  sprite_map.pixels[..256].fill(0x0001);
+  // two overlapping 16x16 sprites, and an 8x8 one over the edge of tile 1
+  for (n, x, size) in [(8, 0, 1), (9, 8, 1), (10, 196, 0)] {
+    let sprite = &mut sprite_map.sprites[n];
+    sprite.x = x;
+    sprite.y = 0;
+    sprite.control = memory::SPRITE_ENABLE | (size << memory::SPRITE_SIZE_SHIFT);
+  }
+  let tile_map = Arc::new(RwLock::new(tile_map));
+  let sprite_map = Arc::new(RwLock::new(sprite_map));
+  let palette = memory::Palette::from_maps(&tile_map.read().unwrap(), &sprite_map.read().unwrap());
+  let collisions = Arc::new(RwLock::new(memory::Collisions::new()));
+  let video = video::VideoMemory {
+    frame_buffer: Arc::new(RwLock::new(frame_buffer)),
+    tile_map,
+    sprite_map,
+    palette: Arc::new(RwLock::new(palette)),
+    collisions: Arc::clone(&collisions),
+    ..blank_video()
+  };
+  let mut renderer = video::Renderer::new(video, Arc::new(RwLock::new(video::Frame::new())));
+  assert!(renderer.detect_collisions(0));
+  assert!(!renderer.detect_collisions(1));
+
+  let mut registers = devices::CollisionRegisters::new(Arc::clone(&collisions));
+  assert_eq!(registers.read(0), 0x0300);
+  assert_eq!(registers.peek(0), 0);
+  assert_eq!(registers.read(2), 0x0400);
+  assert_eq!(registers.peek(4 + 2 * 8), 0x0200);
+  assert_eq!(registers.read(4 + 2 * 9), 0x0100);
+  assert!(renderer.detect_collisions(2));
+}
+
+#[test]
+fn bitmap_mode_test() {
+  use std::sync::{Arc, RwLock};
+  use ::image::Rgba;
+  use bus::Device;
+  let bitmap = Arc::new(RwLock::new(memory::Bitmap::new()));
+  let bank = Arc::new(RwLock::new(0));
+  let mut window = devices::BitmapWindow::new(Arc::clone(&bitmap), Arc::clone(&bank));
+  // the second pixel of the first line, and the last pixel of the bitmap through the last bank
+  window.write(0, 0x0020);
+  *bank.write().unwrap() = 9;
+  window.write(4799 - 9 * 0x200, 0x1000);
+  window.write(0x1FF, 0xFFFF); // past the end
+  assert_eq!(window.read(0x1FF), 0);
+  assert_eq!(bitmap.read().unwrap().get_pixel(1, 0), 2);
+  assert_eq!(bitmap.read().unwrap().get_pixel(159, 119), 1);
+  bitmap.write().unwrap().set_pixel(2, 0, 1);
+  assert_eq!(bitmap.read().unwrap().get_word(0), 0x0120);
+
+  let mut palette = memory::Palette::from_maps(&memory::TileMap::new(128), &memory::SpriteMap::new());
+  palette.colors[1] = 0x000F;
+  palette.colors[2] = 0x00F0;
+  let video = video::VideoMemory {
+    palette: Arc::new(RwLock::new(palette)),
+    video_mode_register: Arc::new(RwLock::new(memory::VIDEO_MODE_BITMAP)),
+    bitmap,
+    ..blank_video()
+  };
+  let frame = Arc::new(RwLock::new(video::Frame::new()));
+  let mut renderer = video::Renderer::new(video, Arc::clone(&frame));
+  renderer.render_line(3);
+  renderer.finish_frame(1);
+  let frame = frame.read().unwrap();
+  // each pixel of the bitmap is 4x4 pixels of the screen
+  assert_eq!(*frame.pixels.get_pixel(3, 3), Rgba([0, 0, 0, 255]));
+  assert_eq!(*frame.pixels.get_pixel(4, 3), Rgba([0, 240, 0, 255]));
+  assert_eq!(*frame.pixels.get_pixel(11, 3), Rgba([240, 0, 0, 255]));
+}
+
+#[test]
+fn text_mode_test() {
+  use std::sync::{Arc, RwLock};
+  use ::image::Rgba;
+  use bus::Device;
+  let grid = Arc::new(RwLock::new(memory::Bitmap::new()));
+  let console = Arc::new(RwLock::new(memory::Console::new()));
+  console.write().unwrap().control = memory::TEXT_ECHO;
+  let mut uart = devices::ConsoleUart::new(Arc::clone(&console), Arc::clone(&grid));
+  for c in b"Hi\n" {
+    uart.write(0, u16::from(*c));
+  }
+  assert_eq!(grid.read().unwrap().get_cell(0, 0), 0x0748);
+  assert_eq!(grid.read().unwrap().get_cell(1, 0), 0x0769);
+  assert_eq!((console.read().unwrap().cursor_x, console.read().unwrap().cursor_y), (0, 1));
+
+  // a new line on the last row scrolls the text up
+  grid.write().unwrap().set_cell(0, 59, 0x0758);
+  console.write().unwrap().cursor_y = 59;
+  uart.write(0, u16::from(b'\n'));
+  assert_eq!(grid.read().unwrap().get_cell(0, 58), 0x0758);
+  assert_eq!(grid.read().unwrap().get_cell(0, 59), 0x0720);
+  assert_eq!(console.read().unwrap().cursor_y, 59);
+  let mut registers = devices::TextRegisters::new(Arc::clone(&console), Arc::clone(&grid));
+  registers.write(4, 1);
+  assert_eq!(grid.read().unwrap().get_cell(0, 57), 0x0758);
+  // a cursor written off the screen stays on it
+  registers.write(1, 0xFFFF);
+  assert_eq!(registers.read(1), 59);
+  uart.write(0, u16::from(b'\n'));
+  assert_eq!(console.read().unwrap().cursor_y, 59);
+
+  // white on blue, with the cursor under the next cell
+  grid.write().unwrap().set_cell(0, 0, 0x1F41);
+  grid.write().unwrap().set_cell(1, 0, 0x0720);
+  registers.write(0, 1);
+  registers.write(1, 0);
+  registers.write(2, memory::TEXT_CURSOR);
+  let video = video::VideoMemory {
+    video_mode_register: Arc::new(RwLock::new(memory::VIDEO_MODE_TEXT)),
+    bitmap: grid,
+    console,
+    ..blank_video()
+  };
+  let frame = Arc::new(RwLock::new(video::Frame::new()));
+  let mut renderer = video::Renderer::new(video, Arc::clone(&frame));
+  renderer.render_line(0);
+  renderer.render_line(7);
+  renderer.finish_frame(1);
+  let frame = frame.read().unwrap();
+  assert_eq!(*frame.pixels.get_pixel(0, 0), Rgba([0, 0, 160, 255]));
+  assert_eq!(*frame.pixels.get_pixel(2, 0), Rgba([240, 240, 240, 255]));
+  assert_eq!(*frame.pixels.get_pixel(8, 7), Rgba([160, 160, 160, 255]));
+  assert_eq!(*frame.pixels.get_pixel(16, 7), Rgba([0, 0, 0, 255]));
+}
+
+#[test]
+fn dma_test() {
+  use std::sync::{Arc, RwLock};
+  use bus::Device;
+  let dma = Arc::new(RwLock::new(dma::Dma::new()));
+  dma.write().unwrap().set_cycles_per_word(2);
+  let mut controller = dma::DmaController::new(Arc::clone(&dma));
+  // two rows of three words, from a sheet 8 words wide into one 16 words wide
+  for (offset, value) in [(0, 0x100), (1, 0x200), (2, 3), (3, 2), (4, 8), (5, 16)] {
+    controller.write(offset, value);
+  }
+  controller.write(7, dma::DMA_COPY);
+  assert_eq!(controller.read(7), dma::DMA_BUSY);
+  assert_eq!(dma.write().unwrap().tick(11), None);
+  let transfer = dma.write().unwrap().tick(1).unwrap();
+  assert_eq!(controller.read(7), 0);
+  let words: Vec<(u16, Result<u16, u16>)> = transfer.words().collect();
+  assert_eq!(words, vec![
+    (0x200, Err(0x100)), (0x201, Err(0x101)), (0x202, Err(0x102)),
+    (0x210, Err(0x108)), (0x211, Err(0x109)), (0x212, Err(0x10A)),
+  ]);
+}
+
+#[test]
+fn dma_interrupt_test() {
+  // fill 4 words at 0x9000 with 7 and exit with one of them from the completion interrupt
+  let mut cpu = program("dma_interrupt_test", &[
+    0x6BC0,    // lui r2, 0x3C0 (0xF000)
+    0x77C1,    // lui r5, 0x3C1 (0xF040)
+    0x6E40,    // lui r3, 0x240 (0x9000)
+    0x8EA1,    // sw r3, r5, 0x21 (destination)
+    0x3004,    // addi r4, r0, 4
+    0x92A2,    // sw r4, r5, 0x22 (length)
+    0x3007,    // addi r4, r0, 7
+    0x92A6,    // sw r4, r5, 0x26 (fill value)
+    0x300F,    // addi r4, r0, 15
+    0x9112,    // sw r4, r2, 0x12 (vector)
+    0x3008,    // addi r4, r0, 8
+    0x9110,    // sw r4, r2, 0x10 (enable)
+    0x3002,    // addi r4, r0, 2
+    0x92A7,    // sw r4, r5, 0x27 (fill)
+    0xC37F,    // jmp -1
+    0xAD83,    // lw r3, r3, 3
+    0xE070,    // sys EXIT
+  ]);
+  cpu.set_dma_cycles(10);
+  cpu.set_max_cycles(Some(10_000));
+  assert_eq!(cpu.run(false), 7);
+}
+
+#[test]
+fn dma_protection_test() {
+  // fill the second word of the program, which is protected
+  let mut cpu = program("dma_protection_test", &[
+    0x77C1,    // lui r5, 0x3C1 (0xF040)
+    0x3001,    // addi r4, r0, 1
+    0x92A1,    // sw r4, r5, 0x21 (destination)
+    0x92A2,    // sw r4, r5, 0x22 (length)
+    0x3002,    // addi r4, r0, 2
+    0x92A7,    // sw r4, r5, 0x27 (fill)
+    0xC37F,    // jmp -1
+  ]);
+  cpu.protect_code(vec![]);
+  cpu.set_max_cycles(Some(10_000));
+  match cpu.run_until_stop(false) {
+    StopReason::ProtectionFault(address, _) => assert_eq!(address, 0x0001),
+    reason => panic!("expected a protection fault, got {}", reason),
+  }
+}
+
+#[test]
+fn debug_viewers_test() {
+  use std::sync::{Arc, RwLock};
+  use ::image::Rgba;
+  let mut tile_map = memory::TileMap::new(128);
+  tile_map.tiles[1].pixels[0] = 0x000F;
+  let mut sprite_map = memory::SpriteMap::new();
+  sprite_map.sprites[0].x = 10;
+  sprite_map.sprites[0].y = 20;
+  let palette = memory::Palette::from_maps(&tile_map, &sprite_map);
+  let video = video::VideoMemory {
+    tile_map: Arc::new(RwLock::new(tile_map)),
+    hscroll_register: Arc::new(RwLock::new(8)),
+    scale_register: Arc::new(RwLock::new(1)),
+    sprite_map: Arc::new(RwLock::new(sprite_map)),
+    palette: Arc::new(RwLock::new(palette)),
+    ..blank_video()
+  };
+  // 16x8 cells of a tile magnified 4 times and its index
+  let sheet = viewers::tile_sheet(&video);
+  assert_eq!(sheet.dimensions(), (16 * 36, 8 * 44));
+  assert_eq!(*sheet.get_pixel(36 + 2 + 3, 2 + 3), Rgba([240, 0, 0, 255]));
+  assert_eq!(*sheet.get_pixel(36 + 2 + 4, 2), Rgba([0, 0, 0, 255]));
+
+  // at scale 1 the screen shows 320x240 of the background, from x = -8, wrapping around
+  let background = viewers::tile_map(&video);
+  let yellow = Rgba([255, 255, 0, 255]);
+  assert_eq!(background.dimensions(), (1024, 512));
+  assert_eq!(*background.get_pixel(1016, 100), yellow);
+  assert_eq!(*background.get_pixel(311, 100), yellow);
+  assert_eq!(*background.get_pixel(100, 239), yellow);
+  assert_eq!(*background.get_pixel(100, 240), Rgba([0, 0, 0, 255]));
+
+  // the boxes are in screen pixels, so twice the sprite's position and size
+  let mut screen = viewers::snapshot(&video);
+  viewers::draw_sprite_boxes(&mut screen, &video);
+  let magenta = Rgba([255, 0, 255, 255]);
+  assert_eq!(*screen.get_pixel(20, 40), magenta);
+  assert_eq!(*screen.get_pixel(83, 103), magenta);
+  assert_ne!(*screen.get_pixel(21, 41), magenta);
+
+  let dir = std::env::temp_dir().join("jpeb_views_test");
+  viewers::export(&video, dir.to_str().unwrap()).unwrap();
+  for name in ["tiles.png", "sprites.png", "tilemap.png"] {
+    assert!(dir.join(name).exists());
+  }
+}
+
+#[test]
+fn speed_control_test() {
+  use clock::{SpeedControl, SpeedMode};
+  let mut control = SpeedControl::default();
+  control.toggle(SpeedMode::Turbo);
+  assert_eq!(control.mode, SpeedMode::Turbo);
+  control.toggle(SpeedMode::SlowMotion);
+  assert_eq!(control.mode, SpeedMode::SlowMotion);
+  control.toggle(SpeedMode::SlowMotion);
+  assert_eq!(control.mode, SpeedMode::Normal);
+}
+
+#[test]
+fn throttle_test() {
+  use std::sync::{Arc, RwLock};
+  use std::time::{Duration, Instant};
+  use clock::{SpeedControl, SpeedMode, Throttle};
+  let control = Arc::new(RwLock::new(SpeedControl::default()));
+  let mut throttle = Throttle::new(Some(1_000_000), Arc::clone(&control));
+  assert_eq!(throttle.paced_hz(), Some(1_000_000.0));
+
+  // 50000 cycles at 1 MHz take at least 50 ms
+  let start = Instant::now();
+  for cycle_count in (0..=50_000).step_by(100) {
+    throttle.pace(cycle_count);
+  }
+  assert!(start.elapsed() >= Duration::from_millis(50));
+
+  // the modes take effect at the next check
+  control.write().unwrap().toggle(SpeedMode::SlowMotion);
+  throttle.pace(60_000);
+  assert_eq!(throttle.paced_hz(), Some(250_000.0));
+  control.write().unwrap().toggle(SpeedMode::Turbo);
+  throttle.pace(70_000);
+  assert_eq!(throttle.paced_hz(), None);
+  control.write().unwrap().toggle(SpeedMode::Turbo);
+  throttle.pace(80_000);
+  assert_eq!(throttle.paced_hz(), Some(1_000_000.0));
+
+  // pausing holds the emulator until the window resumes it
+  control.write().unwrap().toggle(SpeedMode::Paused);
+  let resume = std::thread::spawn({
+    let control = Arc::clone(&control);
+    move || {
+      std::thread::sleep(Duration::from_millis(20));
+      control.write().unwrap().toggle(SpeedMode::Paused);
+    }
+  });
+  let start = Instant::now();
+  throttle.pace(90_000);
+  assert!(start.elapsed() >= Duration::from_millis(20));
+  assert_eq!(control.read().unwrap().mode, SpeedMode::Normal);
+  resume.join().unwrap();
+}
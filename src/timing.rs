@@ -0,0 +1,108 @@
+use std::ops::RangeInclusive;
+
+use crate::config::{self, Config, Section};
+
+// how many cycles each class of instruction takes, plus extra wait states
+// for memory accesses that land in slow regions. The default model charges
+// one cycle for everything, which is what the emulator always counted.
+//
+// A timing file looks like
+//   [cycles]
+//   load = 2
+//   branch_taken = 3
+//   [wait_states]
+//   "0xC000-0xEFFF" = 1  # video memory
+pub struct TimingModel {
+    pub alu: u64,
+    pub add_immediate: u64,
+    pub load_upper_immediate: u64,
+    pub store: u64,
+    pub load: u64,
+    pub branch_taken: u64,
+    pub branch_not_taken: u64,
+    pub jalr: u64,
+    pub exception: u64,
+    wait_states: Vec<WaitState>,
+}
+
+struct WaitState {
+    range: RangeInclusive<u16>,
+    cycles: u64,
+}
+
+impl Default for TimingModel {
+    fn default() -> TimingModel {
+        TimingModel {
+            alu: 1,
+            add_immediate: 1,
+            load_upper_immediate: 1,
+            store: 1,
+            load: 1,
+            branch_taken: 1,
+            branch_not_taken: 1,
+            jalr: 1,
+            exception: 1,
+            wait_states: vec![],
+        }
+    }
+}
+
+impl TimingModel {
+    pub fn load(path: &str) -> Result<TimingModel, String> {
+        let config = Config::load(path)?;
+        let mut model = TimingModel::default();
+        model.configure(config.section("cycles"), config.section("wait_states"))
+            .map_err(|e| format!("{path}: {e}"))?;
+        Ok(model)
+    }
+
+    // overrides the defaults with the entries of a [cycles] and a [wait_states] section
+    pub fn configure(&mut self, cycles: Option<&Section>, wait_states: Option<&Section>) -> Result<(), String> {
+        for entry in cycles.map(|section| section.entries.as_slice()).unwrap_or_default() {
+            let class = match entry.key.as_str() {
+                "alu" => &mut self.alu,
+                "add_immediate" => &mut self.add_immediate,
+                "load_upper_immediate" => &mut self.load_upper_immediate,
+                "store" => &mut self.store,
+                "load" => &mut self.load,
+                "branch_taken" => &mut self.branch_taken,
+                "branch_not_taken" => &mut self.branch_not_taken,
+                "jalr" => &mut self.jalr,
+                "exception" => &mut self.exception,
+                _ => return Err(entry.error("unknown instruction class")),
+            };
+            *class = entry.as_u64()?;
+        }
+        for entry in wait_states.map(|section| section.entries.as_slice()).unwrap_or_default() {
+            let range = config::parse_range(&entry.key)
+                .ok_or(entry.error("expected an address range like 0xC000-0xDFFF"))?;
+            self.wait_states.push(WaitState { range, cycles: entry.as_u64()? });
+        }
+        Ok(())
+    }
+
+    // cycles taken by an instruction fetched from pc, where address is the
+    // memory it loaded or stored and taken says whether it changed the flow
+    pub fn cycles(&self, instr: u16, pc: u16, address: Option<u16>, taken: bool) -> u64 {
+        let base = match instr >> 13 {
+            0 => self.alu,
+            1 => self.add_immediate,
+            3 => self.load_upper_immediate,
+            4 => self.store,
+            5 => self.load,
+            6 if taken => self.branch_taken,
+            6 => self.branch_not_taken,
+            7 if instr & 0x007F == 0 => self.jalr,
+            7 => self.exception,
+            _ => 1,
+        };
+        base + self.wait_cycles(pc) + address.map_or(0, |address| self.wait_cycles(address))
+    }
+
+    fn wait_cycles(&self, address: u16) -> u64 {
+        self.wait_states.iter()
+            .filter(|wait_state| wait_state.range.contains(&address))
+            .map(|wait_state| wait_state.cycles)
+            .sum()
+    }
+}
@@ -0,0 +1,496 @@
+use ::image::{ImageBuffer, Rgba};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::bus::Device;
+use crate::interrupts::{Interrupts, IRQ_VBLANK, IRQ_LINE, IRQ_COLLISION};
+use crate::memory::*;
+use crate::font;
+
+pub const SCREEN_WIDTH: u32 = 640;
+pub const SCREEN_HEIGHT: u32 = 480;
+
+// a frame is this many lines, of which the first ones are shown
+// and the rest are the vertical blank
+const LINES_PER_FRAME : u64 = 525;
+const VISIBLE_LINES : u64 = SCREEN_HEIGHT as u64;
+// a line compare value that matches every line
+const EVERY_LINE : u16 = 0xFFFF;
+// without a clock to derive it from, a frame lasts this many cycles
+pub const DEFAULT_FRAME_CYCLES : u64 = 100_000;
+// each pixel of the bitmap is this many pixels of the screen across and down
+const BITMAP_PIXEL_SIZE : u32 = SCREEN_WIDTH / BITMAP_WIDTH;
+const CHAR_SIZE : u32 = SCREEN_WIDTH / TEXT_COLUMNS;
+// the colours of text mode attributes, as 0x0BGR
+const TEXT_COLORS : [u16; 16] = [
+    0x000, 0xA00, 0x0A0, 0xAA0, 0x00A, 0xA0A, 0x05A, 0xAAA,
+    0x555, 0xF55, 0x5F5, 0xFF5, 0x55F, 0xF5F, 0x5FF, 0xFFF,
+];
+
+// how the video timing is driven, shared with the window
+pub struct VideoControl {
+    frame_cycles: AtomicU64,
+    rendering: AtomicBool,     // whether anyone looks at the frames
+    window_driven: AtomicBool, // frames start when the window asks for one
+    frame_requests: AtomicU64,
+}
+
+// the picture finished at the start of the last vertical blank
+pub struct Frame {
+    pub number: u64,
+    pub pixels: ImageBuffer<Rgba<u8>, Vec<u8>>,
+}
+
+// the memory the picture is made from, shared with the devices that write it
+#[derive(Clone)]
+pub struct VideoMemory {
+    pub frame_buffer: Arc<RwLock<FrameBuffer>>,
+    pub tile_map: Arc<RwLock<TileMap>>,
+    pub vscroll_register: Arc<RwLock<u16>>,
+    pub hscroll_register: Arc<RwLock<u16>>,
+    pub scale_register: Arc<RwLock<u16>>,
+    pub sprite_map: Arc<RwLock<SpriteMap>>,
+    pub palette: Arc<RwLock<Palette>>,
+    pub collisions: Arc<RwLock<Collisions>>,
+    pub video_mode_register: Arc<RwLock<u16>>,
+    pub bitmap: Arc<RwLock<Bitmap>>,
+    pub console: Arc<RwLock<Console>>,
+}
+
+// Draws the background and sprites one line at a time, from the
+// registers as they are when the beam gets to the line.
+pub struct Renderer {
+    video: VideoMemory,
+    frame: Arc<RwLock<Frame>>,
+    back: ImageBuffer<Rgba<u8>, Vec<u8>>, // the frame being drawn
+    priority: Vec<bool>, // the pixels of the line a tile above the sprites covers
+    backdrop: Vec<bool>, // the pixels of the line where the background is 0
+    coverage: Vec<u32>,  // the sprites at each pixel of the logical line
+}
+
+// The vblank status, frame counter, scanline and line compare registers.
+// The beam moves through the lines of a frame with the emulated cycles,
+// so what a program sees and the frames it renders only depend on what it
+// executes, unless a window without a target clock starts the frames at
+// its own 60 Hz. Each visible line is drawn as the beam leaves it, and a
+// frame is finished, and the vblank interrupt raised, when the vertical
+// blank starts. The line compare interrupt is raised as the beam leaves the
+// line in the compare register, or every line if it holds 0xFFFF, so a
+// handler that changes the scroll registers affects the lines after it.
+pub struct VideoTiming {
+    control: Arc<VideoControl>,
+    interrupts: Arc<Interrupts>,
+    renderer: Renderer,
+    frame_cycles: u64,
+    cycle: u64, // into the current frame
+    line: u64,  // the beam is on
+    frame: u16,
+    line_compare: u16,
+    frame_requests: u64, // requests from the window already handled
+}
+
+impl Default for VideoControl {
+    fn default() -> VideoControl {
+        VideoControl {
+            frame_cycles: AtomicU64::new(DEFAULT_FRAME_CYCLES),
+            rendering: AtomicBool::new(false),
+            window_driven: AtomicBool::new(false),
+            frame_requests: AtomicU64::new(0),
+        }
+    }
+}
+
+impl VideoControl {
+    pub fn set_frame_cycles(&self, frame_cycles: u64) {
+        self.frame_cycles.store(frame_cycles.max(LINES_PER_FRAME), Ordering::Relaxed);
+    }
+
+    pub fn set_rendering(&self, rendering: bool) {
+        self.rendering.store(rendering, Ordering::Relaxed);
+    }
+
+    pub fn set_window_driven(&self, window_driven: bool) {
+        self.window_driven.store(window_driven, Ordering::Relaxed);
+    }
+
+    // called by the window every time it wants a new frame
+    pub fn request_frame(&self) {
+        self.frame_requests.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Frame {
+    pub fn new() -> Frame {
+        Frame { number: 0, pixels: ImageBuffer::new(FRAME_WIDTH, FRAME_HEIGHT) }
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Frame {
+        Frame::new()
+    }
+}
+
+impl Renderer {
+    pub fn new(video: VideoMemory, frame: Arc<RwLock<Frame>>) -> Renderer {
+        Renderer {
+            video,
+            frame,
+            back: ImageBuffer::new(FRAME_WIDTH, FRAME_HEIGHT),
+            priority: vec![false; SCREEN_WIDTH as usize],
+            backdrop: vec![false; SCREEN_WIDTH as usize],
+            coverage: vec![0; SCREEN_WIDTH as usize],
+        }
+    }
+
+    // draws a line of the screen
+    pub fn render_line(&mut self, y: u32) {
+        let scale = 1 << (*self.video.scale_register.read().unwrap() as u32);
+        // the line of the logical screen shown here
+        let logical_y = y / scale;
+        let video_mode = *self.video.video_mode_register.read().unwrap();
+        match video_mode {
+            VIDEO_MODE_BITMAP => self.draw_bitmap(y),
+            VIDEO_MODE_TEXT => self.draw_text(y),
+            _ => self.draw_background(y, logical_y, scale),
+        }
+        self.draw_sprites(y, logical_y, scale);
+    }
+
+    // hands the drawn frame to the window
+    pub fn finish_frame(&mut self, number: u64) {
+        let mut frame = self.frame.write().unwrap();
+        std::mem::swap(&mut frame.pixels, &mut self.back);
+        frame.number = number;
+    }
+
+    // finds the sprites touching each other or the background on a line, returns whether any of them are new
+    pub fn detect_collisions(&mut self, y: u32) -> bool {
+        let scale = 1 << (*self.video.scale_register.read().unwrap() as u32);
+        let logical_y = y / scale;
+        let sprite_map = self.video.sprite_map.read().unwrap();
+        let palette = self.video.palette.read().unwrap();
+        let fb = self.video.frame_buffer.read().unwrap();
+        let tile_map = self.video.tile_map.read().unwrap();
+        let scroll_x = *self.video.hscroll_register.read().unwrap() as i32;
+        let scroll_y = *self.video.vscroll_register.read().unwrap() as i32;
+        let background_y = (logical_y as i32 - scroll_y).rem_euclid(FRAME_HEIGHT as i32) as u32;
+        let bitmap = self.video.bitmap.read().unwrap();
+        let video_mode = *self.video.video_mode_register.read().unwrap();
+        // whether the background isn't 0 under a pixel of the logical line, in text mode whether a character is
+        let touches_background = |x: u32| match video_mode {
+            VIDEO_MODE_BITMAP => bitmap.get_pixel(x * scale / BITMAP_PIXEL_SIZE, y / BITMAP_PIXEL_SIZE) != 0,
+            VIDEO_MODE_TEXT => text_pixel(&bitmap, x * scale, y),
+            _ => {
+                let background_x = (x as i32 - scroll_x).rem_euclid(FRAME_WIDTH as i32) as u32;
+                background_pixel(&fb, &tile_map, background_x, background_y).2 != 0
+            },
+        };
+        let width = SCREEN_WIDTH / scale;
+        self.coverage[..width as usize].fill(0);
+        let mut background = 0;
+        for (n, sprite) in sprite_map.sprites.iter().enumerate() {
+            let Some(row) = sprite_row(sprite, logical_y) else {
+                continue;
+            };
+            for px in 0..sprite.size() {
+                let x = sprite.x as u32 + px;
+                if x >= width || sprite_pixel(&sprite_map, &palette, n, row, px).is_none() {
+                    continue;
+                }
+                self.coverage[x as usize] |= 1 << n;
+                if touches_background(x) {
+                    background |= 1 << n;
+                }
+            }
+        }
+        let mut collisions = self.video.collisions.write().unwrap();
+        let before = (collisions.sprites, collisions.background, collisions.pairs.clone());
+        collisions.background |= background;
+        for &sprites in self.coverage[..width as usize].iter().filter(|sprites| sprites.count_ones() > 1) {
+            collisions.sprites |= sprites;
+            for n in 0..collisions.pairs.len() {
+                if sprites & (1 << n) != 0 {
+                    collisions.pairs[n] |= sprites & !(1 << n);
+                }
+            }
+        }
+        (collisions.sprites, collisions.background, collisions.pairs.clone()) != before
+    }
+
+    fn draw_background(&mut self, y: u32, logical_y: u32, scale: u32) {
+        let fb = self.video.frame_buffer.read().unwrap();
+        let tile_map = self.video.tile_map.read().unwrap();
+        let palette = self.video.palette.read().unwrap();
+        let scroll_x = *self.video.hscroll_register.read().unwrap() as i32;
+        let scroll_y = *self.video.vscroll_register.read().unwrap() as i32;
+        // the background wraps around, so scrolling moves its pixels right and down
+        let background_y = (logical_y as i32 - scroll_y).rem_euclid(FRAME_HEIGHT as i32) as u32;
+        for x in 0..SCREEN_WIDTH {
+            let background_x = (x as i32 / scale as i32 - scroll_x).rem_euclid(FRAME_WIDTH as i32) as u32;
+            let (tile_ptr, attributes, tile_pixel) = background_pixel(&fb, &tile_map, background_x, background_y);
+            self.backdrop[x as usize] = tile_pixel == 0;
+            self.back.put_pixel(x, y, color(tile_color(&palette, tile_ptr, attributes, tile_pixel)));
+            self.priority[x as usize] = attributes & TILE_PRIORITY != 0;
+        }
+    }
+
+    // a line of the bitmap, with its pixels looked up in the first 16 colours of the palette
+    fn draw_bitmap(&mut self, y: u32) {
+        let bitmap = self.video.bitmap.read().unwrap();
+        let palette = self.video.palette.read().unwrap();
+        for x in 0..SCREEN_WIDTH {
+            let pixel = bitmap.get_pixel(x / BITMAP_PIXEL_SIZE, y / BITMAP_PIXEL_SIZE);
+            self.back.put_pixel(x, y, color(palette.colors[usize::from(pixel)]));
+            self.backdrop[x as usize] = pixel == 0;
+            self.priority[x as usize] = false;
+        }
+    }
+
+    // a line of the text grid in the built in font, with the cursor as an underline
+    fn draw_text(&mut self, y: u32) {
+        let grid = self.video.bitmap.read().unwrap();
+        let console = self.video.console.read().unwrap();
+        let row = y / CHAR_SIZE;
+        let cursor_line = console.control & TEXT_CURSOR != 0 && u32::from(console.cursor_y) == row && y % CHAR_SIZE == CHAR_SIZE - 1;
+        for x in 0..SCREEN_WIDTH {
+            let column = x / CHAR_SIZE;
+            let attribute = grid.get_cell(column, row) >> 8;
+            let foreground = text_pixel(&grid, x, y) || (cursor_line && u32::from(console.cursor_x) == column);
+            let color_index = if foreground { attribute & 0xf } else { (attribute >> 4) & 0xf };
+            self.back.put_pixel(x, y, color(TEXT_COLORS[usize::from(color_index)]));
+            self.backdrop[x as usize] = !foreground;
+            self.priority[x as usize] = false;
+        }
+    }
+
+    fn draw_sprites(&mut self, y: u32, logical_y: u32, scale: u32) {
+        let sprite_map = self.video.sprite_map.read().unwrap();
+        let palette = self.video.palette.read().unwrap();
+        // later sprites are drawn over earlier ones
+        for (n, sprite) in sprite_map.sprites.iter().enumerate() {
+            let Some(row) = sprite_row(sprite, logical_y) else {
+                continue;
+            };
+            let behind = sprite.control & SPRITE_BEHIND != 0;
+            for px in 0..sprite.size() {
+                let Some(tile_pixel) = sprite_pixel(&sprite_map, &palette, n, row, px) else {
+                    continue;
+                };
+                let final_x: u32 = sprite.x as u32 + px;
+                for i in 0..scale {
+                    let screen_x: u32 = final_x * scale + i;
+                    if screen_x >= SCREEN_WIDTH {
+                        continue;
+                    }
+                    let covered = self.priority[screen_x as usize] || (behind && !self.backdrop[screen_x as usize]);
+                    if !covered {
+                        self.back.put_pixel(screen_x, y, color(tile_pixel));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// the tile at a pixel of the background, its attributes and the pixel's value
+pub fn background_pixel(fb: &FrameBuffer, tile_map: &TileMap, background_x: u32, background_y: u32) -> (u8, u8, u16) {
+    let tile_ptr = fb.get_tile(background_x / TILE_SIZE, background_y / TILE_SIZE);
+    let attributes = fb.get_attributes(background_x / TILE_SIZE, background_y / TILE_SIZE);
+    let mut px = background_x % TILE_SIZE;
+    let mut py = background_y % TILE_SIZE;
+    if attributes & TILE_HFLIP != 0 {
+        px = TILE_SIZE - 1 - px;
+    }
+    if attributes & TILE_VFLIP != 0 {
+        py = TILE_SIZE - 1 - py;
+    }
+    (tile_ptr, attributes, tile_map.tiles[tile_ptr as usize].pixels[(px + py * TILE_SIZE) as usize])
+}
+
+// the colour of a pixel of a tile, through the palette if the tile is indexed
+pub fn tile_color(palette: &Palette, tile_ptr: u8, attributes: u8, mut pixel: u16) -> u16 {
+    let indexed = palette.indexed_tiles[tile_ptr as usize];
+    if indexed && attributes & TILE_PALETTE != 0 {
+        pixel |= PALETTE_SIZE as u16 / 2;
+    }
+    palette.resolve(pixel, indexed)
+}
+
+// whether a pixel of the screen is part of a character in text mode
+fn text_pixel(grid: &Bitmap, x: u32, y: u32) -> bool {
+    let cell = grid.get_cell(x / CHAR_SIZE, y / CHAR_SIZE);
+    font::pixel(cell as u8, x % CHAR_SIZE, y % CHAR_SIZE)
+}
+
+// the row of its pixels an enabled sprite shows on a line of the logical screen
+fn sprite_row(sprite: &Sprite, logical_y: u32) -> Option<u32> {
+    if sprite.control & SPRITE_ENABLE == 0 {
+        return None;
+    }
+    let size = sprite.size();
+    let row = logical_y.wrapping_sub(u32::from(sprite.y));
+    if row >= size {
+        None
+    } else if sprite.control & SPRITE_VFLIP != 0 {
+        Some(size - 1 - row)
+    } else {
+        Some(row)
+    }
+}
+
+// the colour of the nth sprite at a pixel of a row, None where it is transparent
+fn sprite_pixel(sprite_map: &SpriteMap, palette: &Palette, n: usize, row: u32, px: u32) -> Option<u16> {
+    let sprite = &sprite_map.sprites[n];
+    let size = sprite.size();
+    let data_x = if sprite.control & SPRITE_HFLIP != 0 { size - 1 - px } else { px };
+    let pixel = palette.resolve(sprite_map.get_sprite_pixel(sprite, data_x, row), palette.indexed_sprites[n]);
+    let transparent = (pixel & 0xf000) == 0xf000;
+    (!transparent).then_some(pixel)
+}
+
+// a 12 bit 0x0BGR pixel as rgba
+pub fn color(pixel: u16) -> Rgba<u8> {
+    let red = (pixel & 0x000f) as u8 * 16;
+    let green = ((pixel & 0x00f0) >> 4) as u8 * 16;
+    let blue = ((pixel & 0x0f00) >> 8) as u8 * 16;
+    Rgba([red, green, blue, 255])
+}
+
+impl VideoTiming {
+    pub fn new(control: Arc<VideoControl>, interrupts: Arc<Interrupts>, renderer: Renderer) -> VideoTiming {
+        VideoTiming {
+            control,
+            interrupts,
+            renderer,
+            frame_cycles: 0, // the clock isn't known yet, see tick
+            cycle: 0,
+            line: 0,
+            frame: 0,
+            line_compare: EVERY_LINE,
+            frame_requests: 0,
+        }
+    }
+
+    // the cycle of the frame a line starts at
+    fn line_start(&self, line: u64) -> u64 {
+        self.frame_cycles * line / LINES_PER_FRAME
+    }
+
+    fn in_vblank(&self) -> bool {
+        self.line >= VISIBLE_LINES
+    }
+
+    // the beam leaves the line it is on
+    fn end_line(&mut self) {
+        self.cycle = self.line_start(self.line + 1);
+        if self.line < VISIBLE_LINES && self.control.rendering.load(Ordering::Relaxed) {
+            self.renderer.render_line(self.line as u32);
+        }
+        if self.line < VISIBLE_LINES && self.renderer.detect_collisions(self.line as u32) {
+            self.interrupts.raise(IRQ_COLLISION);
+        }
+        if self.line_compare == EVERY_LINE || u64::from(self.line_compare) == self.line {
+            self.interrupts.raise(IRQ_LINE);
+        }
+        self.line += 1;
+        if self.line == VISIBLE_LINES {
+            self.start_vblank();
+        } else if self.line == LINES_PER_FRAME {
+            self.start_frame();
+        }
+    }
+
+    fn start_vblank(&mut self) {
+        self.frame = self.frame.wrapping_add(1);
+        if self.control.rendering.load(Ordering::Relaxed) {
+            self.renderer.finish_frame(u64::from(self.frame));
+        }
+        self.interrupts.raise(IRQ_VBLANK);
+    }
+
+    fn start_frame(&mut self) {
+        self.cycle = 0;
+        self.line = 0;
+        self.frame_cycles = self.control.frame_cycles.load(Ordering::Relaxed);
+    }
+
+    // moves the beam along by this many cycles
+    fn advance(&mut self, cycles: u64) {
+        let mut remaining = cycles;
+        loop {
+            let line_end = self.line_start(self.line + 1);
+            if self.cycle + remaining < line_end {
+                self.cycle += remaining;
+                return;
+            }
+            remaining -= line_end - self.cycle;
+            self.end_line();
+        }
+    }
+
+    fn tick_cycle_driven(&mut self, cycles: u64) {
+        // whole frames skipped while the program slept are only counted
+        let skipped = cycles / self.frame_cycles;
+        self.frame = self.frame.wrapping_add(skipped as u16);
+        self.advance(cycles % self.frame_cycles);
+    }
+
+    fn tick_window_driven(&mut self, cycles: u64) {
+        let requests = self.control.frame_requests.load(Ordering::Relaxed);
+        if requests != self.frame_requests {
+            self.frame_requests = requests;
+            // the window wants the next frame now, so the beam races through the rest of it
+            if self.in_vblank() {
+                self.start_frame();
+            }
+            while !self.in_vblank() {
+                self.end_line();
+            }
+            return;
+        }
+        // the visible lines last until the window asks for the next frame
+        if self.in_vblank() {
+            self.advance(cycles);
+        } else {
+            let last_cycle = self.line_start(VISIBLE_LINES) - 1;
+            self.advance(cycles.min(last_cycle.saturating_sub(self.cycle)));
+        }
+    }
+}
+
+impl Device for VideoTiming {
+    fn name(&self) -> &str {
+        "video timing"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        match offset {
+            0 => u16::from(self.in_vblank()),
+            1 => self.frame,
+            2 => self.line as u16,
+            _ => self.line_compare,
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u16) {
+        if offset == 3 {
+            self.line_compare = data;
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if self.frame_cycles == 0 {
+            self.start_frame();
+        }
+        if self.control.window_driven.load(Ordering::Relaxed) {
+            self.tick_window_driven(cycles);
+        } else {
+            self.tick_cycle_driven(cycles);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use ::image::{ImageBuffer, Rgba};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use crate::font;
+use crate::memory::*;
+use crate::video::{background_pixel, color, tile_color, Frame, Renderer, VideoMemory, SCREEN_WIDTH, SCREEN_HEIGHT};
+
+// Debug views of the video memory: every tile with its index, the sprites'
+// positions and bounding boxes over the screen, and the whole background
+// with the part the scroll and scale registers show marked on it.
+
+pub type View = ImageBuffer<Rgba<u8>, Vec<u8>>;
+
+const TILE_COLUMNS : u32 = 16;
+const TILE_ZOOM : u32 = 4;
+const CELL_WIDTH : u32 = TILE_SIZE * TILE_ZOOM + 4;
+const CELL_HEIGHT : u32 = TILE_SIZE * TILE_ZOOM + GLYPH_SIZE + 4; // room for the index below the tile
+const GLYPH_SIZE : u32 = 8;
+const SHEET_BACKGROUND : Rgba<u8> = Rgba([40, 40, 40, 255]);
+const LABEL_COLOR : Rgba<u8> = Rgba([255, 255, 255, 255]);
+const BOX_COLOR : Rgba<u8> = Rgba([255, 0, 255, 255]);
+const VIEWPORT_COLOR : Rgba<u8> = Rgba([255, 255, 0, 255]);
+
+// all the tiles in rows of 16, each magnified with its index below it
+pub fn tile_sheet(video: &VideoMemory) -> View {
+    let tile_map = video.tile_map.read().unwrap();
+    let palette = video.palette.read().unwrap();
+    let rows = (tile_map.tiles.len() as u32).div_ceil(TILE_COLUMNS);
+    let mut sheet = ImageBuffer::from_pixel(TILE_COLUMNS * CELL_WIDTH, rows * CELL_HEIGHT, SHEET_BACKGROUND);
+    for (i, tile) in tile_map.tiles.iter().enumerate() {
+        let left = (i as u32 % TILE_COLUMNS) * CELL_WIDTH + 2;
+        let top = (i as u32 / TILE_COLUMNS) * CELL_HEIGHT + 2;
+        for y in 0..TILE_SIZE * TILE_ZOOM {
+            for x in 0..TILE_SIZE * TILE_ZOOM {
+                let pixel = tile.pixels[(x / TILE_ZOOM + y / TILE_ZOOM * TILE_SIZE) as usize];
+                sheet.put_pixel(left + x, top + y, color(tile_color(&palette, i as u8, 0, pixel)));
+            }
+        }
+        draw_label(&mut sheet, left, top + TILE_SIZE * TILE_ZOOM + 1, &i.to_string(), LABEL_COLOR);
+    }
+    sheet
+}
+
+// outlines the enabled sprites where they are on the screen, with their number and position
+pub fn draw_sprite_boxes(view: &mut View, video: &VideoMemory) {
+    let sprite_map = video.sprite_map.read().unwrap();
+    let scale = 1 << (*video.scale_register.read().unwrap() as u32);
+    for (n, sprite) in sprite_map.sprites.iter().enumerate() {
+        if sprite.control & SPRITE_ENABLE == 0 {
+            continue;
+        }
+        let left = i64::from(sprite.x) * scale;
+        let top = i64::from(sprite.y) * scale;
+        let size = i64::from(sprite.size()) * scale;
+        draw_outline(view, left, top, size, size, BOX_COLOR, false);
+        let label = format!("{} {},{}", n, sprite.x, sprite.y);
+        // above the box, or inside it at the top of the screen
+        let label_top = if top >= i64::from(GLYPH_SIZE) { top - i64::from(GLYPH_SIZE) } else { top + 1 };
+        if let (Ok(x), Ok(y)) = (u32::try_from(left), u32::try_from(label_top)) {
+            draw_label(view, x, y, &label, BOX_COLOR);
+        }
+    }
+}
+
+// the whole background, with an outline around what the screen shows of it
+pub fn tile_map(video: &VideoMemory) -> View {
+    let fb = video.frame_buffer.read().unwrap();
+    let tile_map = video.tile_map.read().unwrap();
+    let palette = video.palette.read().unwrap();
+    let mut view = ImageBuffer::new(FRAME_WIDTH, FRAME_HEIGHT);
+    for y in 0..FRAME_HEIGHT {
+        for x in 0..FRAME_WIDTH {
+            let (tile_ptr, attributes, pixel) = background_pixel(&fb, &tile_map, x, y);
+            view.put_pixel(x, y, color(tile_color(&palette, tile_ptr, attributes, pixel)));
+        }
+    }
+    // the screen's top left shows the background at minus the scroll registers
+    let scale = 1 << (*video.scale_register.read().unwrap() as u32);
+    let left = -i64::from(*video.hscroll_register.read().unwrap());
+    let top = -i64::from(*video.vscroll_register.read().unwrap());
+    let width = i64::from(SCREEN_WIDTH / scale);
+    let height = i64::from(SCREEN_HEIGHT / scale);
+    draw_outline(&mut view, left, top, width, height, VIEWPORT_COLOR, true);
+    view
+}
+
+// the screen as it is drawn from the video memory right now
+pub fn snapshot(video: &VideoMemory) -> View {
+    let frame = Arc::new(RwLock::new(Frame::new()));
+    let mut renderer = Renderer::new(video.clone(), Arc::clone(&frame));
+    for y in 0..SCREEN_HEIGHT {
+        renderer.render_line(y);
+    }
+    renderer.finish_frame(0);
+    let frame = frame.read().unwrap();
+    let mut screen = ImageBuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+    for y in 0..SCREEN_HEIGHT {
+        for x in 0..SCREEN_WIDTH {
+            screen.put_pixel(x, y, *frame.pixels.get_pixel(x, y));
+        }
+    }
+    screen
+}
+
+// saves tiles.png, sprites.png and tilemap.png in a directory
+pub fn export(video: &VideoMemory, dir: &str) -> Result<(), String> {
+    let dir = Path::new(dir);
+    std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut sprites = snapshot(video);
+    draw_sprite_boxes(&mut sprites, video);
+    let views = [("tiles.png", tile_sheet(video)), ("sprites.png", sprites), ("tilemap.png", tile_map(video))];
+    for (name, view) in views {
+        let path = dir.join(name);
+        view.save(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+// a rectangle's edges, either cut off at the sides of the view or wrapping around them
+fn draw_outline(view: &mut View, left: i64, top: i64, width: i64, height: i64, pixel: Rgba<u8>, wrap: bool) {
+    let (view_width, view_height) = (i64::from(view.width()), i64::from(view.height()));
+    let mut put = |x: i64, y: i64| {
+        let (x, y) = if wrap { (x.rem_euclid(view_width), y.rem_euclid(view_height)) } else { (x, y) };
+        if (0..view_width).contains(&x) && (0..view_height).contains(&y) {
+            view.put_pixel(x as u32, y as u32, pixel);
+        }
+    };
+    for x in left..left + width {
+        put(x, top);
+        put(x, top + height - 1);
+    }
+    for y in top..top + height {
+        put(left, y);
+        put(left + width - 1, y);
+    }
+}
+
+// text in the built in font, cut off at the sides of the view
+fn draw_label(view: &mut View, left: u32, top: u32, text: &str, pixel: Rgba<u8>) {
+    for (i, c) in text.bytes().enumerate() {
+        for y in 0..GLYPH_SIZE {
+            for x in 0..GLYPH_SIZE {
+                let (px, py) = (left + i as u32 * GLYPH_SIZE + x, top + y);
+                if font::pixel(c, x, y) && px < view.width() && py < view.height() {
+                    view.put_pixel(px, py, pixel);
+                }
+            }
+        }
+    }
+}